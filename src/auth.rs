@@ -1,18 +1,25 @@
 extern crate yup_oauth2;
-use std::ops::{Deref};
+use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Once;
+use std::time::Duration;
+use yup_oauth2::authenticator::Authenticator;
 use yup_oauth2::authenticator_delegate::{
     InstalledFlowDelegate,
 };
+use yup_oauth2::error::AuthErrorCode;
 use async_trait::async_trait;
+use serde::Deserialize;
+use crate::commands::EXIT_CANCELLED;
+use crate::token_storage::TokenStore;
 
 #[derive(Copy, Clone)]
 pub struct WebLauncherInstallFlowDelegate;
 
 impl WebLauncherInstallFlowDelegate {
-    async fn wait_for_complete<'a>(
-        &'a self,
+    async fn wait_for_complete(
+        &self,
         need_code: bool
     ) -> Result<String, String> {
         use tokio::io::AsyncBufReadExt;
@@ -45,9 +52,144 @@ impl InstalledFlowDelegate for WebLauncherInstallFlowDelegate {
         need_code: bool,
     ) -> Pin<Box<dyn Future<Output = Result<String, String>> + Send + 'a>> {
         if url.is_empty() {
-            panic!("Invalid authentication url provided: {}", url);
+            return Box::pin(async {
+                Err("Invalid authentication url provided".to_string())
+            });
+        }
+        if webbrowser::open(url).is_err() {
+            println!("Open this URL in your browser to continue: {}", url);
         }
-        webbrowser::open(url).unwrap();
         Box::pin(self.wait_for_complete(need_code))
     }
-}
\ No newline at end of file
+}
+
+/// Why the interactive authorization flow didn't produce a token.
+#[derive(Debug)]
+pub enum AuthFlowError {
+    TimedOut(Duration),
+    Cancelled,
+    /// Signing in is required, but conf-sync is running non-interactively.
+    ReauthRequired,
+    Failed(yup_oauth2::Error),
+}
+
+impl fmt::Display for AuthFlowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthFlowError::TimedOut(timeout) => write!(
+                f,
+                "Authorization was not completed within {} seconds",
+                timeout.as_secs(),
+            ),
+            AuthFlowError::Cancelled => write!(f, "Authorization was cancelled"),
+            AuthFlowError::ReauthRequired => write!(
+                f,
+                "No usable credentials are stored; run conf-sync interactively to sign in again",
+            ),
+            AuthFlowError::Failed(err) => write!(f, "Authorization failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for AuthFlowError {}
+
+/// How `authorize` is allowed to involve the user.
+#[derive(Debug, Copy, Clone)]
+pub struct FlowOptions {
+    /// How long to wait for the user to finish signing in.
+    pub timeout: Duration,
+    /// Whether a browser sign-in may be started at all.
+    pub interactive: bool,
+}
+
+/// Make sure a token for `scopes` is available, running the interactive flow
+/// if needed. If the stored refresh token has been revoked or has expired it
+/// is purged from `storage` and the user is asked to sign in again.
+pub async fn authorize<C, T>(
+    auth: &Authenticator<C>,
+    storage: &TokenStore,
+    scopes: &[T],
+    options: FlowOptions,
+) -> Result<(), AuthFlowError>
+where
+    C: hyper::client::connect::Connect + Clone + Send + Sync + 'static,
+    T: AsRef<str>,
+{
+    if !options.interactive {
+        let usable = storage
+            .find_token(scopes)
+            .await
+            .is_some_and(|json_token| json_token.is_usable());
+        if !usable {
+            return Err(AuthFlowError::ReauthRequired);
+        }
+    }
+
+    match run_flow(auth, scopes, options.timeout).await {
+        Err(AuthFlowError::Failed(yup_oauth2::Error::AuthError(err)))
+            if err.error == AuthErrorCode::InvalidGrant =>
+        {
+            storage
+                .remove_token(scopes)
+                .await
+                .map_err(|e| AuthFlowError::Failed(yup_oauth2::Error::OtherError(e)))?;
+
+            if !options.interactive {
+                return Err(AuthFlowError::ReauthRequired);
+            }
+            println!("The stored credentials are no longer valid; signing in again.");
+            run_flow(auth, scopes, options.timeout).await
+        }
+        result => result,
+    }
+}
+
+/// Run the token request, which may start the browser flow. The flow is
+/// abandoned after `timeout` or on Ctrl-C; dropping it shuts down the loopback
+/// listener waiting for the browser redirect.
+async fn run_flow<C, T>(
+    auth: &Authenticator<C>,
+    scopes: &[T],
+    timeout: Duration,
+) -> Result<(), AuthFlowError>
+where
+    C: hyper::client::connect::Connect + Clone + Send + Sync + 'static,
+    T: AsRef<str>,
+{
+    let result = tokio::select! {
+        result = tokio::time::timeout(timeout, auth.token(scopes)) => match result {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(err)) => Err(AuthFlowError::Failed(err)),
+            Err(_) => Err(AuthFlowError::TimedOut(timeout)),
+        },
+        _ = tokio::signal::ctrl_c() => Err(AuthFlowError::Cancelled),
+    };
+
+    // Listening for Ctrl-C replaces the default handler for the rest of the
+    // process, so keep Ctrl-C terminating conf-sync once the flow is over.
+    // One listener does for every flow.
+    static EXIT_ON_CTRL_C: Once = Once::new();
+    EXIT_ON_CTRL_C.call_once(|| {
+        tokio::spawn(async {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(EXIT_CANCELLED);
+            }
+        });
+    });
+
+    result
+}
+
+/// Pull the `email` claim out of an OpenID Connect id token. The token is only
+/// decoded for display; its signature isn't verified.
+pub fn id_token_email(id_token: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Claims {
+        email: Option<String>,
+    }
+
+    let payload = id_token.split('.').nth(1)?;
+    let decoded = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let claims: Claims = serde_json::from_slice(&decoded).ok()?;
+    claims.email
+}
@@ -0,0 +1,139 @@
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use crate::config_handler::SettingsFormat;
+use crate::token_storage::TokenBackend;
+
+/// Synchronize configuration files through Google Drive.
+#[derive(Parser, Debug)]
+#[clap(name = "conf-sync", version, about)]
+pub struct Cli {
+    /// Read settings from this file instead of the default settings.toml
+    #[clap(long, global = true, value_name = "PATH", env = "CONF_SYNC_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Use the settings of this profile from the settings file
+    #[clap(long, global = true, value_name = "NAME", env = "CONF_SYNC_PROFILE")]
+    pub profile: Option<String>,
+
+    /// Never open a browser to sign in; fail instead when signing in again
+    /// is required
+    #[clap(long, global = true)]
+    pub non_interactive: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Write the default settings to a new settings file
+    Init {
+        /// Format to write the settings in: toml, yaml or json. Defaults to
+        /// the extension of --config, or toml
+        #[clap(long)]
+        format: Option<SettingsFormat>,
+    },
+    /// Merge the settings shared from another machine into the settings file
+    Pull,
+    /// Manage the Google account conf-sync is authorized with
+    #[clap(subcommand)]
+    Auth(AuthCommand),
+    /// Inspect and change the settings
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+    /// List, inspect and select settings profiles
+    #[clap(subcommand)]
+    Profile(ProfileCommand),
+}
+
+impl Command {
+    /// Whether the command can only run with valid settings. Commands that
+    /// report on or repair the settings run regardless.
+    pub fn requires_valid_settings(&self) -> bool {
+        !matches!(
+            self,
+            Command::Init { .. }
+                | Command::Auth(AuthCommand::ImportClient { .. })
+                | Command::Config(
+                    ConfigCommand::Validate
+                        | ConfigCommand::Show { .. }
+                        | ConfigCommand::Get { .. }
+                        | ConfigCommand::Set { .. }
+                        | ConfigCommand::Edit
+                )
+                | Command::Profile(ProfileCommand::Use { .. })
+        )
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AuthCommand {
+    /// Show the signed-in account, granted scopes and token expiry
+    Status,
+    /// List every stored token
+    List,
+    /// Remove every stored token, signing conf-sync out
+    Clear,
+    /// Move stored tokens from one storage backend to another
+    MigrateTokens {
+        /// Backend to move tokens out of: keyring, encrypted-file, file or memory
+        #[clap(long)]
+        from: TokenBackend,
+        /// Backend to move tokens into: keyring, encrypted-file, file or memory
+        #[clap(long)]
+        to: TokenBackend,
+    },
+    /// Use your own OAuth client, from a client secret file downloaded from
+    /// the Google Cloud console
+    ImportClient {
+        /// Path to the downloaded client_secret JSON file
+        path: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Check the settings and report every problem found
+    Validate,
+    /// Print the settings in effect, after merging every settings file
+    Show {
+        /// Show which settings file, profile or environment variable each
+        /// value came from
+        #[clap(long)]
+        origin: bool,
+    },
+    /// Print the value in effect for one setting
+    Get {
+        /// Setting to print, as section.key, e.g. gdrive.mode
+        key: String,
+    },
+    /// Change one setting in the settings file, keeping its comments and
+    /// formatting
+    Set {
+        /// Setting to change, as section.key, or profiles.<name>.section.key
+        /// to change it for a profile
+        key: String,
+        /// New value, written as TOML; anything that isn't valid TOML is
+        /// taken as a string
+        value: String,
+    },
+    /// Open the settings file in $VISUAL or $EDITOR, and check it once the
+    /// editor exits
+    Edit,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ProfileCommand {
+    /// List the profiles defined in the settings file
+    List,
+    /// Show the settings a profile results in
+    Show {
+        /// Profile to show; defaults to the selected one
+        name: Option<String>,
+    },
+    /// Select the profile used when --profile isn't given
+    Use {
+        /// Profile to use; leave out to use the settings without a profile
+        name: Option<String>,
+    },
+}
@@ -0,0 +1,278 @@
+use std::path::Path;
+use anyhow::{anyhow, bail, Context};
+use yup_oauth2::storage::TokenStorage;
+use crate::auth::id_token_email;
+use crate::cli::AuthCommand;
+use crate::config_handler::{ConfigHandler, OauthConfig};
+use crate::scopes::{self, Feature};
+use crate::fs::{file_exists, secure_remove_file};
+use crate::token_storage::{KeychainStorage, TokenBackend, TokenStore};
+use super::{EXIT_FAILURE, EXIT_OK, EXIT_REAUTH_REQUIRED};
+
+pub async fn run(cfg: &mut ConfigHandler, command: AuthCommand) -> i32 {
+    let result = match command {
+        AuthCommand::Status => status(cfg).await,
+        AuthCommand::List => list(cfg).await,
+        AuthCommand::Clear => clear(cfg).await,
+        AuthCommand::MigrateTokens { from, to } => migrate_tokens(cfg, from, to).await,
+        AuthCommand::ImportClient { path } => import_client(cfg, &path).await,
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{:#}", err);
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// Report on the stored token used for syncing. Returns
+/// `EXIT_REAUTH_REQUIRED` when there is no token, or when it has expired and
+/// can't be refreshed.
+async fn status(cfg: &ConfigHandler) -> anyhow::Result<i32> {
+    let storage = cfg.inspect_token_store().await?;
+
+    let drive_scopes = cfg.feature_scopes(cfg.drive_feature());
+    let json_token = match storage.find_token(&drive_scopes).await {
+        Some(json_token) => json_token,
+        None => {
+            println!("Not signed in. Run conf-sync to authorize an account.");
+            return Ok(EXIT_REAUTH_REQUIRED);
+        }
+    };
+    let token = &json_token.token;
+
+    // The drive token only carries an id token if the account scopes were
+    // granted alongside it; otherwise look for the account display token.
+    let account_token = storage
+        .find_token(&cfg.feature_scopes(Feature::AccountDisplay))
+        .await;
+    let account = token
+        .id_token
+        .as_deref()
+        .or_else(|| account_token.as_ref().and_then(|t| t.token.id_token.as_deref()))
+        .and_then(id_token_email)
+        .unwrap_or_else(|| "unknown (no email in id token)".into());
+
+    let expiry = match token.expires_at {
+        Some(expires_at) if token.is_expired() => format!("expired at {}", expires_at),
+        Some(expires_at) => format!("expires at {}", expires_at),
+        None => "no expiry".into(),
+    };
+
+    let has_refresh_token = token.refresh_token.is_some();
+
+    // Scopes for enabled features that haven't been used yet are requested
+    // on first use, so they won't show up in any stored token until then.
+    let granted = |scopes: &[String], scope: &str| {
+        scopes.iter().any(|s| s == scopes::canonical(scope))
+    };
+    let pending: Vec<String> = cfg
+        .required_scopes()
+        .into_iter()
+        .filter(|scope| {
+            !granted(&json_token.scopes, scope)
+                && !account_token.as_ref().is_some_and(|t| granted(&t.scopes, scope))
+        })
+        .collect();
+
+    println!("Account:       {}", account);
+    println!("Token store:   {}", storage.describe());
+    println!("Scopes:        {}", json_token.scopes.join(", "));
+    if !pending.is_empty() {
+        println!("Not granted:   {} (requested on first use)", pending.join(", "));
+    }
+    println!("Access token:  {}", expiry);
+    println!(
+        "Refresh token: {}",
+        if has_refresh_token { "present" } else { "missing" },
+    );
+
+    if token.is_expired() && !has_refresh_token {
+        println!("The access token has expired and can't be refreshed; sign in again.");
+        return Ok(EXIT_REAUTH_REQUIRED);
+    }
+
+    Ok(EXIT_OK)
+}
+
+async fn list(cfg: &ConfigHandler) -> anyhow::Result<i32> {
+    let storage = cfg.inspect_token_store().await?;
+    let tokens = storage.tokens().await?;
+
+    println!("Token store: {}", storage.describe());
+    if tokens.is_empty() {
+        println!("No stored tokens.");
+        return Ok(EXIT_OK);
+    }
+
+    for json_token in tokens.iter() {
+        let token = &json_token.token;
+        let account = token
+            .id_token
+            .as_deref()
+            .and_then(id_token_email)
+            .unwrap_or_else(|| "unknown account".into());
+        let state = match (token.is_expired(), token.refresh_token.is_some()) {
+            (false, _) => "valid",
+            (true, true) => "expired, refreshable",
+            (true, false) => "expired",
+        };
+        println!();
+        println!("{:016x}  {} ({})", json_token.hash.0, account, state);
+        println!("    {}", json_token.scopes.join(", "));
+    }
+
+    Ok(EXIT_OK)
+}
+
+async fn clear(cfg: &ConfigHandler) -> anyhow::Result<i32> {
+    let storage = cfg.open_token_store().await?;
+    let removed = storage.clear().await?;
+    println!("Removed {} stored token(s) from {}", removed, storage.describe());
+    Ok(EXIT_OK)
+}
+
+/// Move every token from one backend to another. The source is only emptied
+/// once each token has been read back from the destination.
+async fn migrate_tokens(
+    cfg: &ConfigHandler,
+    from: TokenBackend,
+    to: TokenBackend,
+) -> anyhow::Result<i32> {
+    if from == to {
+        bail!("--from and --to must name different token stores");
+    }
+    if from == TokenBackend::Memory {
+        bail!("The memory store doesn't keep tokens between runs; there is nothing to migrate");
+    }
+    if to == TokenBackend::Memory {
+        bail!(
+            "The memory store doesn't keep tokens between runs, so moving them there would discard them; \
+             remove them with `conf-sync auth clear` instead"
+        );
+    }
+
+    let source = open_for_migration(cfg, from, to).await?;
+    let destination = open_for_migration(cfg, to, from).await?;
+    let tokens = source.tokens().await?;
+
+    for json_token in tokens.iter() {
+        let scopes: Vec<&str> = json_token.scopes.iter().map(String::as_str).collect();
+        destination
+            .set(&scopes, json_token.token.clone())
+            .await
+            .with_context(|| format!("Unable to copy tokens into {}", destination.describe()))?;
+    }
+
+    let copied = destination.reopen().await?;
+    for json_token in tokens.iter() {
+        match copied.find_token(&json_token.scopes).await {
+            Some(copy) if copy.token == json_token.token => {},
+            _ => bail!(
+                "The token for {} could not be read back from {}; {} was left untouched",
+                json_token.scopes.join(", "),
+                destination.describe(),
+                source.describe(),
+            ),
+        }
+    }
+
+    source.clear().await?;
+
+    // Every token in the source's file was copied, so it can go, unless the
+    // destination or the configured token store still uses it.
+    let configured = cfg.settings.tokens.storage;
+    let in_use = [
+        destination.filename().map(Path::to_path_buf),
+        (configured != TokenBackend::Memory).then(|| cfg.token_path(configured)),
+    ];
+    if let Some(path) = source.filename() {
+        if !in_use.iter().flatten().any(|in_use| in_use == path) && file_exists(path).await {
+            secure_remove_file(path)
+                .await
+                .with_context(|| format!("Unable to remove {}", path.display()))?;
+        }
+    }
+
+    println!(
+        "Moved {} token(s) from {} to {}",
+        tokens.len(),
+        source.describe(),
+        destination.describe(),
+    );
+    if to != cfg.settings.tokens.storage {
+        println!("Set storage = \"{}\" under [tokens] in the settings to use it.", to);
+    }
+    Ok(EXIT_OK)
+}
+
+/// Open `backend` to move tokens to or from `other`. The keyring is opened
+/// with its token file, so tokens saved there are moved as well, unless
+/// `other` uses the same file.
+async fn open_for_migration(
+    cfg: &ConfigHandler,
+    backend: TokenBackend,
+    other: TokenBackend,
+) -> anyhow::Result<TokenStore> {
+    if backend == TokenBackend::Keyring {
+        let filename = cfg.token_path(backend);
+        let shared = matches!(other, TokenBackend::File | TokenBackend::EncryptedFile)
+            && cfg.token_path(other) == filename;
+        let storage = KeychainStorage::new(cfg.keychain(), Some(filename).filter(|_| !shared)).await?;
+        if !storage.is_available() {
+            bail!("The keyring is unavailable, so tokens can't be moved in or out of it");
+        }
+        return Ok(TokenStore::Keyring(storage));
+    }
+    Ok(TokenStore::open(backend, cfg.token_path(backend), cfg.keychain()).await?)
+}
+
+/// Import a Google client secret file. The secret goes into the keychain, or
+/// a file in the data directory when there is none, and only the non-secret
+/// client details are written to the settings file.
+async fn import_client(cfg: &mut ConfigHandler, path: &Path) -> anyhow::Result<i32> {
+    let secret = yup_oauth2::read_application_secret(path)
+        .await
+        .with_context(|| format!("Unable to read client file {}", path.display()))?;
+
+    let missing: Vec<&str> = [
+        ("client_id", secret.client_id.is_empty()),
+        ("client_secret", secret.client_secret.is_empty()),
+        ("auth_uri", secret.auth_uri.is_empty()),
+        ("token_uri", secret.token_uri.is_empty()),
+        ("redirect_uris", secret.redirect_uris.is_empty()),
+    ]
+    .iter()
+    .filter(|(_, is_missing)| *is_missing)
+    .map(|(field, _)| *field)
+    .collect();
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "{} is missing required fields: {}",
+            path.display(),
+            missing.join(", "),
+        ));
+    }
+
+    let stored_in = cfg.store_client_secret(&secret.client_id, &secret.client_secret).await?;
+
+    let _lock = cfg.lock_settings().await?;
+    let current = cfg.oauth_config.clone();
+    cfg.set_oauth_config(OauthConfig {
+        client_id: secret.client_id.clone(),
+        client_secret: String::new(),
+        project_id: secret.project_id.unwrap_or_default(),
+        auth_uri: secret.auth_uri,
+        token_uri: secret.token_uri,
+        auth_provider_x509_cert_url: secret.auth_provider_x509_cert_url.unwrap_or_default(),
+        redirect_uris: secret.redirect_uris,
+        ..current
+    })?;
+    cfg.save().await?;
+
+    println!("Imported OAuth client {}", secret.client_id);
+    println!("The client secret is stored in {}.", stored_in);
+    Ok(EXIT_OK)
+}
@@ -0,0 +1,206 @@
+use std::io;
+use std::path::Path;
+use anyhow::{bail, Context};
+use crate::cli::ConfigCommand;
+use crate::config_handler::{ConfigError, ConfigHandler, Problem};
+use crate::fs::write_file_atomic;
+use super::{EXIT_CONFIG, EXIT_FAILURE, EXIT_OK};
+
+/// Used when neither `$VISUAL` nor `$EDITOR` is set.
+const DEFAULT_EDITOR: &str = "vi";
+
+pub async fn run(cfg: &mut ConfigHandler, command: ConfigCommand, interactive: bool) -> i32 {
+    let result = match command {
+        ConfigCommand::Validate => Ok(validate(cfg)),
+        ConfigCommand::Show { origin } => show(cfg, origin),
+        ConfigCommand::Get { key } => get(cfg, &key),
+        ConfigCommand::Set { key, value } => set(cfg, &key, &value).await,
+        ConfigCommand::Edit => edit(cfg, interactive).await,
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{:#}", err);
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// Report every problem with the settings, including a client secret that
+/// can't be found anywhere. Returns `EXIT_CONFIG` when there are any.
+fn validate(cfg: &ConfigHandler) -> i32 {
+    let mut problems = cfg.problems();
+    if let Err(e) = cfg.get_app_secret() {
+        problems.push(Problem {
+            key: "oauth.client_secret".into(),
+            message: e.to_string(),
+        });
+    }
+
+    if problems.is_empty() {
+        println!("{} is valid.", cfg.base_paths.conf_path.display());
+        return EXIT_OK;
+    }
+    eprintln!("{}", ConfigError::Invalid {
+        path: cfg.base_paths.conf_path.clone(),
+        problems,
+    });
+    EXIT_CONFIG
+}
+
+/// Print the settings in effect, optionally with where each value came from.
+fn show(cfg: &ConfigHandler, origin: bool) -> anyhow::Result<i32> {
+    if !origin {
+        print!("{}", cfg.settings_toml()?);
+        return Ok(EXIT_OK);
+    }
+
+    for (key, value) in cfg.settings_values()? {
+        println!("{} = {}  # {}", key, value, cfg.origin(&key));
+    }
+    Ok(EXIT_OK)
+}
+
+/// Print the value in effect for `key`. Strings are printed without quotes,
+/// so the value can be used as is in scripts.
+fn get(cfg: &ConfigHandler, key: &str) -> anyhow::Result<i32> {
+    match cfg.get_value(key)? {
+        toml::Value::String(value) => println!("{}", value),
+        value => println!("{}", value),
+    }
+    Ok(EXIT_OK)
+}
+
+/// Change `key` in the user's settings file. The change is undone when the
+/// settings no longer load with it, or it introduces a problem.
+async fn set(cfg: &mut ConfigHandler, key: &str, value: &str) -> anyhow::Result<i32> {
+    let conf_path = cfg.base_paths.conf_path.clone();
+    let _lock = cfg.lock_settings().await?;
+    let previous = cfg.clone();
+    cfg.set_value(key, value)?;
+    cfg.save().await?;
+
+    match check_changes(&previous).await {
+        Ok(changed) => {
+            println!("Set {} in {}.", key, conf_path.display());
+            warn_about_problems(&changed);
+            Ok(EXIT_OK)
+        }
+        Err(e) => {
+            previous.save().await.context("Unable to undo the change")?;
+            eprintln!("{}", e);
+            eprintln!("{} was left unchanged.", conf_path.display());
+            Ok(EXIT_CONFIG)
+        }
+    }
+}
+
+/// Open the user's settings file in an editor, and check it once the editor
+/// exits. Changes that break the settings can be edited again, or are
+/// discarded.
+async fn edit(cfg: &mut ConfigHandler, interactive: bool) -> anyhow::Result<i32> {
+    let _lock = cfg.lock_settings().await?;
+    let conf_path = &cfg.base_paths.conf_path;
+    let original = match tokio::fs::read(conf_path).await {
+        Ok(contents) => Some(contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            return Err(e).with_context(|| format!("Unable to read {}", conf_path.display()));
+        }
+    };
+
+    loop {
+        run_editor(conf_path).await?;
+
+        let e = match check_changes(cfg).await {
+            Ok(changed) => {
+                println!("Saved {}.", conf_path.display());
+                warn_about_problems(&changed);
+                return Ok(EXIT_OK);
+            }
+            Err(e) => e,
+        };
+        eprintln!("{}", e);
+        if interactive && confirm("Edit the settings again? [Y/n] ").await? {
+            continue;
+        }
+
+        match &original {
+            Some(contents) => write_file_atomic(conf_path, contents).await,
+            None => tokio::fs::remove_file(conf_path).await,
+        }
+        .with_context(|| format!("Unable to restore {}", conf_path.display()))?;
+        eprintln!("Discarded the changes to {}.", conf_path.display());
+        return Ok(EXIT_CONFIG);
+    }
+}
+
+/// Load the settings files again after `before` was loaded and they were
+/// changed. Fails when they no longer load, or with the problems the change
+/// introduced; problems the settings already had are left for the user.
+pub async fn check_changes(before: &ConfigHandler) -> Result<ConfigHandler, ConfigError> {
+    let after = before.reload().await?;
+    let known: Vec<String> = before.problems().iter().map(Problem::to_string).collect();
+    let problems: Vec<Problem> = after
+        .problems()
+        .into_iter()
+        .filter(|problem| !known.contains(&problem.to_string()))
+        .collect();
+    if !problems.is_empty() {
+        return Err(ConfigError::Invalid {
+            path: after.base_paths.conf_path.clone(),
+            problems,
+        });
+    }
+    Ok(after)
+}
+
+fn warn_about_problems(cfg: &ConfigHandler) {
+    if let Err(e) = cfg.validate() {
+        eprintln!("{}", e);
+    }
+}
+
+/// Run `$VISUAL`, `$EDITOR` or vi on `path` and wait for it to exit. The
+/// variables may include arguments, e.g. `code --wait`.
+async fn run_editor(path: &Path) -> anyhow::Result<()> {
+    let editor = ["VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_EDITOR.into());
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or(DEFAULT_EDITOR);
+
+    let status = tokio::process::Command::new(program)
+        .args(words)
+        .arg(path)
+        .status()
+        .await
+        .with_context(|| format!("Unable to run the editor {}", editor))?;
+    if !status.success() {
+        bail!("The editor {} exited with {}; the settings were not checked", editor, status);
+    }
+    Ok(())
+}
+
+/// Ask a yes or no question on the terminal. Yes is the default, unless
+/// there is nothing to read the answer from.
+async fn confirm(question: &str) -> anyhow::Result<bool> {
+    use std::io::Write;
+    use tokio::io::AsyncBufReadExt;
+    eprint!("{}", question);
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    let read = tokio::io::BufReader::new(tokio::io::stdin())
+        .read_line(&mut answer)
+        .await
+        .context("Unable to read the answer")?;
+    if read == 0 {
+        eprintln!();
+        return Ok(false);
+    }
+    Ok(!answer.trim().to_lowercase().starts_with('n'))
+}
@@ -0,0 +1,43 @@
+use std::path::PathBuf;
+use anyhow::bail;
+use crate::config_handler::{ConfigError, ConfigHandler, SettingsFormat};
+use super::{EXIT_CONFIG, EXIT_FAILURE, EXIT_OK};
+
+pub async fn run(conf_path: Option<PathBuf>, format: Option<SettingsFormat>) -> i32 {
+    match init(conf_path, format).await {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{:#}", err);
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// Write the default settings in `format`. A file given with `--config` is
+/// read in the format its extension names, so `format` has to agree with it.
+async fn init(conf_path: Option<PathBuf>, format: Option<SettingsFormat>) -> anyhow::Result<i32> {
+    let format = match (&conf_path, format) {
+        (Some(path), Some(format)) if SettingsFormat::from_path(path) != format => bail!(
+            "{} would be read as {}; give it a .{} extension to write {}",
+            path.display(),
+            SettingsFormat::from_path(path),
+            format.extension(),
+            format,
+        ),
+        (Some(path), None) => SettingsFormat::from_path(path),
+        (_, format) => format.unwrap_or_default(),
+    };
+
+    match ConfigHandler::init(conf_path, format).await {
+        Ok(path) => {
+            println!("Wrote the default settings to {}.", path.display());
+            println!("Import an OAuth client with `conf-sync auth import-client` to finish setting up.");
+            Ok(EXIT_OK)
+        }
+        Err(e @ ConfigError::AlreadyExists { .. }) => {
+            eprintln!("{}", e);
+            Ok(EXIT_CONFIG)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,14 @@
+pub mod auth;
+pub mod config;
+pub mod init;
+pub mod profile;
+
+/// Exit codes follow the BSD `sysexits.h` conventions where one applies.
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+/// The stored credentials can't be used and the user has to sign in again.
+pub const EXIT_REAUTH_REQUIRED: i32 = 77;
+/// The settings file is missing, unreadable or invalid.
+pub const EXIT_CONFIG: i32 = 78;
+/// Interrupted with Ctrl-C, matching the shell's 128 + SIGINT.
+pub const EXIT_CANCELLED: i32 = 130;
@@ -0,0 +1,67 @@
+use anyhow::Context;
+use crate::cli::ProfileCommand;
+use crate::config_handler::ConfigHandler;
+use super::{EXIT_FAILURE, EXIT_OK};
+
+pub async fn run(cfg: &mut ConfigHandler, command: ProfileCommand) -> i32 {
+    let result = match command {
+        ProfileCommand::List => list(cfg),
+        ProfileCommand::Show { name } => show(cfg, name).await,
+        ProfileCommand::Use { name } => use_profile(cfg, name).await,
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{:#}", err);
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// List the defined profiles, marking the selected one.
+fn list(cfg: &ConfigHandler) -> anyhow::Result<i32> {
+    let names = cfg.profile_names();
+    if names.is_empty() {
+        println!("No profiles are defined in {}.", cfg.base_paths.conf_path.display());
+        return Ok(EXIT_OK);
+    }
+
+    for name in names.iter() {
+        let marker = if cfg.profile.as_ref() == Some(name) { "*" } else { " " };
+        println!("{} {}", marker, name);
+    }
+    Ok(EXIT_OK)
+}
+
+/// Print the settings `name` results in, or those of the selected profile.
+async fn show(cfg: &ConfigHandler, name: Option<String>) -> anyhow::Result<i32> {
+    let profile_cfg;
+    let cfg = match name {
+        Some(name) if cfg.profile.as_ref() != Some(&name) => {
+            profile_cfg = cfg.with_profile(name).await?;
+            &profile_cfg
+        }
+        _ => cfg,
+    };
+
+    match &cfg.profile {
+        Some(name) => println!("# Profile: {}", name),
+        None => println!("# No profile"),
+    }
+    print!("{}", cfg.settings_toml()?);
+    Ok(EXIT_OK)
+}
+
+/// Make `name` the profile used when none is given on the command line.
+async fn use_profile(cfg: &mut ConfigHandler, name: Option<String>) -> anyhow::Result<i32> {
+    let _lock = cfg.lock_settings().await?;
+    cfg.set_default_profile(name.clone())?;
+    cfg.save().await.context("Unable to select the profile")?;
+
+    match name {
+        Some(name) => println!("Using profile {}.", name),
+        None => println!("Using the settings without a profile."),
+    }
+    Ok(EXIT_OK)
+}
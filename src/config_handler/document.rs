@@ -0,0 +1,123 @@
+use std::path::Path;
+use toml_edit::{Document, Item, Table, TableLike, Value};
+use super::{table_to_string, ConfigError, SettingsFormat};
+
+/// The user's settings file as written, edited in place so its comments and
+/// formatting survive conf-sync changing a setting. YAML and JSON files are
+/// edited as TOML and converted back when saved.
+#[derive(Debug, Clone, Default)]
+pub struct SettingsDocument {
+    format: SettingsFormat,
+    document: Document,
+}
+
+impl SettingsDocument {
+    /// Parse `contents`, in the format `path`'s extension names.
+    pub fn parse(path: &Path, contents: &str) -> Result<Self, ConfigError> {
+        let syntax_error = |message: String| ConfigError::Syntax {
+            path: path.to_owned(),
+            position: None,
+            message,
+        };
+        let format = SettingsFormat::from_path(path);
+        let document = match format {
+            SettingsFormat::Toml => contents.parse::<Document>(),
+            _ => table_to_string(&format.parse(path, contents)?)
+                .map_err(|e| syntax_error(e.to_string()))?
+                .parse::<Document>(),
+        };
+        match document {
+            Ok(document) => Ok(SettingsDocument { format, document }),
+            Err(e) => Err(syntax_error(e.to_string())),
+        }
+    }
+
+    /// The file's new contents, in its own format.
+    pub fn contents(&self) -> Result<String, String> {
+        match self.format {
+            SettingsFormat::Toml => Ok(self.document.to_string()),
+            format => format.serialize(&self.to_table()?),
+        }
+    }
+
+    /// The settings in the file, without their formatting.
+    pub fn to_table(&self) -> Result<toml::value::Table, String> {
+        match self.document.to_string().parse::<toml::Value>() {
+            Ok(toml::Value::Table(table)) => Ok(table),
+            Ok(_) => unreachable!("a document is always a table"),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Set the value at `keys`, creating the tables above it. A value that is
+    /// replaced keeps the comments around it. Fails with the dotted key of
+    /// anything other than a table in the way.
+    pub fn set(&mut self, keys: &[&str], mut value: Value) -> Result<(), String> {
+        let (key, parents) = keys.split_last().expect("a setting has a key");
+        let table = table_mut(self.document.as_table_mut(), parents)?;
+        // Replacing the item rather than inserting the key again keeps the
+        // comments above the key.
+        match table.get_mut(key) {
+            Some(existing) => {
+                if let Some(existing) = existing.as_value() {
+                    *value.decor_mut() = existing.decor().clone();
+                }
+                *existing = Item::Value(value);
+            }
+            None => {
+                table.insert(key, Item::Value(value));
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `set`, for a value read with the `toml` crate.
+    pub fn set_toml(&mut self, keys: &[&str], value: &toml::Value) -> Result<(), String> {
+        let value = value
+            .to_string()
+            .parse::<Value>()
+            .expect("a toml::Value prints as the TOML for it");
+        self.set(keys, value)
+    }
+
+    /// The item at `keys`, if there is one.
+    pub fn get_mut(&mut self, keys: &[&str]) -> Option<&mut Item> {
+        let (key, parents) = keys.split_last()?;
+        self.parent_mut(parents)?.get_mut(key)
+    }
+
+    /// Remove the value at `keys`, if it is set.
+    pub fn remove(&mut self, keys: &[&str]) {
+        let (key, parents) = keys.split_last().expect("a setting has a key");
+        if let Some(table) = self.parent_mut(parents) {
+            table.remove(key);
+        }
+    }
+
+    fn parent_mut(&mut self, keys: &[&str]) -> Option<&mut dyn TableLike> {
+        let mut table: &mut dyn TableLike = self.document.as_table_mut();
+        for key in keys {
+            table = table.get_mut(key)?.as_table_like_mut()?;
+        }
+        Some(table)
+    }
+}
+
+/// The table at `keys` below `root`, created if it doesn't exist. Tables only
+/// created to hold another one don't get a header of their own.
+fn table_mut<'a>(root: &'a mut Table, keys: &[&str]) -> Result<&'a mut dyn TableLike, String> {
+    let mut table: &mut dyn TableLike = root;
+    for (i, key) in keys.iter().enumerate() {
+        let is_parent = i + 1 < keys.len();
+        table = table
+            .entry(key)
+            .or_insert_with(|| {
+                let mut table = Table::new();
+                table.set_implicit(is_parent);
+                Item::Table(table)
+            })
+            .as_table_like_mut()
+            .ok_or_else(|| keys[..=i].join("."))?;
+    }
+    Ok(table)
+}
@@ -0,0 +1,176 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use super::validate::Problem;
+
+/// Why the settings couldn't be loaded or saved.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The home directory couldn't be determined, so neither could the
+    /// config and data directories.
+    NoHomeDir,
+    /// A directory conf-sync keeps its files in couldn't be created.
+    CreateDir { path: PathBuf, source: io::Error },
+    /// The settings file exists but couldn't be read.
+    Unreadable { path: PathBuf, source: io::Error },
+    /// The settings file couldn't be written.
+    Unwritable { path: PathBuf, source: io::Error },
+    /// New settings were to be written where a settings file already is.
+    AlreadyExists { path: PathBuf },
+    /// The settings file isn't valid TOML. `position` is the 1-based line and
+    /// column of the error, when the parser knows it.
+    Syntax {
+        path: PathBuf,
+        position: Option<(usize, usize)>,
+        message: String,
+    },
+    /// The settings file is valid TOML, but doesn't describe valid settings:
+    /// a field is missing or has the wrong type.
+    Schema { path: PathBuf, message: String },
+    /// The settings file was written by a newer conf-sync, in a layout this
+    /// one doesn't know.
+    UnsupportedVersion { path: PathBuf, version: i64 },
+    /// The selected profile isn't defined in the settings file.
+    UnknownProfile { path: PathBuf, name: String, known: Vec<String> },
+    /// The OAuth client's secret isn't in the settings, and couldn't be read
+    /// from the keychain: it was never imported, or the keychain failed.
+    MissingClientSecret { client_id: String, reason: Option<String> },
+    /// A setting was named that is kept in the keychain rather than in the
+    /// settings.
+    SecretSetting { key: String },
+    /// A setting was named that conf-sync doesn't have.
+    UnknownSetting { key: String },
+    /// The settings were read, but some values can't be used.
+    Invalid { path: PathBuf, problems: Vec<Problem> },
+    /// An environment variable overriding a setting has a value that doesn't
+    /// fit it.
+    Environment { var: String, message: String },
+}
+
+impl ConfigError {
+    pub(super) fn syntax(path: PathBuf, error: toml::de::Error) -> Self {
+        // The message ends with the position, which is reported separately.
+        let message = error.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(at) if error.line_col().is_some() => message[..at].to_owned(),
+            _ => message,
+        };
+        ConfigError::Syntax {
+            path,
+            position: error.line_col().map(|(line, column)| (line + 1, column + 1)),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::NoHomeDir => write!(
+                f,
+                "Unable to determine the home directory to keep settings in",
+            ),
+            ConfigError::CreateDir { path, source } => write!(
+                f,
+                "Unable to create directory {}: {}",
+                path.display(),
+                source,
+            ),
+            ConfigError::Unreadable { path, source } => write!(
+                f,
+                "Unable to read settings from {}: {}",
+                path.display(),
+                source,
+            ),
+            ConfigError::Unwritable { path, source } => write!(
+                f,
+                "Unable to write settings to {}: {}",
+                path.display(),
+                source,
+            ),
+            ConfigError::AlreadyExists { path } => write!(
+                f,
+                "Settings already exist in {}; change them with `conf-sync config edit`",
+                path.display(),
+            ),
+            ConfigError::Syntax { path, position: Some((line, column)), message } => write!(
+                f,
+                "Syntax error in {} at line {}, column {}: {}",
+                path.display(),
+                line,
+                column,
+                message,
+            ),
+            ConfigError::Syntax { path, position: None, message } => write!(
+                f,
+                "Syntax error in {}: {}",
+                path.display(),
+                message,
+            ),
+            ConfigError::Schema { path, message } => write!(
+                f,
+                "Invalid settings in {}: {}",
+                path.display(),
+                message,
+            ),
+            ConfigError::UnsupportedVersion { path, version } => write!(
+                f,
+                "{} has settings version {}, but this conf-sync only reads up to version {}; upgrade conf-sync",
+                path.display(),
+                version,
+                super::schema::SETTINGS_VERSION,
+            ),
+            ConfigError::UnknownProfile { path, name, known } if known.is_empty() => write!(
+                f,
+                "There is no profile '{}' in {}; no profiles are defined",
+                name,
+                path.display(),
+            ),
+            ConfigError::UnknownProfile { path, name, known } => write!(
+                f,
+                "There is no profile '{}' in {}; known profiles are {}",
+                name,
+                path.display(),
+                known.join(", "),
+            ),
+            ConfigError::MissingClientSecret { client_id, reason: None } => write!(
+                f,
+                "No client secret is stored for OAuth client {}; import it with `conf-sync auth import-client` \
+                 or set CONF_SYNC_OAUTH__CLIENT_SECRET",
+                client_id,
+            ),
+            ConfigError::MissingClientSecret { client_id, reason: Some(reason) } => write!(
+                f,
+                "Unable to read the client secret for OAuth client {} from the keychain: {}; import it again with \
+                 `conf-sync auth import-client` or set CONF_SYNC_OAUTH__CLIENT_SECRET",
+                client_id,
+                reason,
+            ),
+            ConfigError::SecretSetting { key } => write!(
+                f,
+                "{} is kept in the keychain, not in the settings; store it with `conf-sync auth import-client`",
+                key,
+            ),
+            ConfigError::UnknownSetting { key } => write!(
+                f,
+                "There is no setting '{}'; see `conf-sync config show` for the settings",
+                key,
+            ),
+            ConfigError::Invalid { path, problems } => {
+                write!(f, "Invalid settings in {}:", path.display())?;
+                for problem in problems.iter() {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            }
+            ConfigError::Environment { var, message } => write!(
+                f,
+                "Invalid value in environment variable {}: {}",
+                var,
+                message,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
@@ -0,0 +1,139 @@
+use std::fmt;
+use std::path::Path;
+use config::{Config, File, FileFormat};
+use toml::value::Table;
+use yaml_rust::{Yaml, YamlEmitter};
+use super::{table_to_string, ConfigError};
+
+/// The formats a settings file can be written in, told apart by its
+/// extension. Only TOML keeps its comments when conf-sync changes a setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SettingsFormat {
+    #[default]
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl std::str::FromStr for SettingsFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "toml" => Ok(SettingsFormat::Toml),
+            "yaml" | "yml" => Ok(SettingsFormat::Yaml),
+            "json" => Ok(SettingsFormat::Json),
+            _ => Err(format!(
+                "unknown settings format '{}', expected one of toml, yaml, json",
+                value,
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SettingsFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+impl SettingsFormat {
+    /// Every format, in the order the settings file is looked for.
+    pub const ALL: [SettingsFormat; 3] = [SettingsFormat::Toml, SettingsFormat::Yaml, SettingsFormat::Json];
+
+    /// The format of the settings file at `path`. Files without a known
+    /// extension are read as TOML.
+    pub fn from_path(path: &Path) -> Self {
+        path.extension()
+            .and_then(|extension| extension.to_str()?.parse().ok())
+            .unwrap_or_default()
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            SettingsFormat::Toml => "toml",
+            SettingsFormat::Yaml => "yaml",
+            SettingsFormat::Json => "json",
+        }
+    }
+
+    /// Every extension a file in this format can have, starting with the one
+    /// new files get.
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            SettingsFormat::Toml => &["toml"],
+            SettingsFormat::Yaml => &["yaml", "yml"],
+            SettingsFormat::Json => &["json"],
+        }
+    }
+
+    /// Parse the contents of the settings file at `path`.
+    pub fn parse(self, path: &Path, contents: &str) -> Result<Table, ConfigError> {
+        let not_a_table = || ConfigError::Schema {
+            path: path.to_owned(),
+            message: "settings must be a table".into(),
+        };
+        let file_format = match self {
+            SettingsFormat::Toml => {
+                let document = contents
+                    .parse::<toml::Value>()
+                    .map_err(|e| ConfigError::syntax(path.to_owned(), e))?;
+                return match document {
+                    toml::Value::Table(document) => Ok(document),
+                    _ => Err(not_a_table()),
+                };
+            }
+            SettingsFormat::Yaml => FileFormat::Yaml,
+            SettingsFormat::Json => FileFormat::Json,
+        };
+
+        let syntax_error = |message: String| ConfigError::Syntax {
+            path: path.to_owned(),
+            position: None,
+            message,
+        };
+        Config::builder()
+            .add_source(File::from_str(contents, file_format))
+            .build()
+            .map_err(|e| syntax_error(e.to_string()))?
+            .try_deserialize()
+            .map_err(|e| ConfigError::Schema { path: path.to_owned(), message: e.to_string() })
+    }
+
+    /// Write `document` out in this format.
+    pub fn serialize(self, document: &Table) -> Result<String, String> {
+        match self {
+            SettingsFormat::Toml => table_to_string(document).map_err(|e| e.to_string()),
+            SettingsFormat::Yaml => {
+                let mut contents = String::new();
+                YamlEmitter::new(&mut contents)
+                    .dump(&to_yaml(&toml::Value::Table(document.clone())))
+                    .map_err(|e| e.to_string())?;
+                contents.push('\n');
+                Ok(contents)
+            }
+            SettingsFormat::Json => {
+                let mut contents = serde_json::to_string_pretty(document).map_err(|e| e.to_string())?;
+                contents.push('\n');
+                Ok(contents)
+            }
+        }
+    }
+}
+
+fn to_yaml(value: &toml::Value) -> Yaml {
+    match value {
+        toml::Value::String(value) => Yaml::String(value.clone()),
+        toml::Value::Integer(value) => Yaml::Integer(*value),
+        toml::Value::Float(value) => Yaml::Real(value.to_string()),
+        toml::Value::Boolean(value) => Yaml::Boolean(*value),
+        toml::Value::Datetime(value) => Yaml::String(value.to_string()),
+        toml::Value::Array(values) => Yaml::Array(values.iter().map(to_yaml).collect()),
+        toml::Value::Table(table) => Yaml::Hash(
+            table
+                .iter()
+                .map(|(key, value)| (Yaml::String(key.clone()), to_yaml(value)))
+                .collect(),
+        ),
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use toml::value::Table;
+use super::{ConfigError, Problem, SettingsFormat};
+
+/// Settings shared by every user of the machine.
+#[cfg(unix)]
+const SYSTEM_SETTINGS: &str = "/etc/conf-sync/settings.toml";
+/// Settings for a directory tree, found by walking up from the current
+/// directory.
+const PROJECT_SETTINGS: &str = ".conf-sync.toml";
+/// What a project's settings may set, as dotted keys and the sections below
+/// them. Anyone can put a `.conf-sync.toml` into a repository, so where
+/// tokens are kept and the OAuth client they are sent to are left to the
+/// user's own settings.
+const PROJECT_KEYS: [&str; 4] = ["version", "gdrive.mode", "gdrive.app_folder", "sync"];
+
+/// Where a setting's value came from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    /// Nothing set it, so it has its default value.
+    Default,
+    /// A settings file.
+    File(PathBuf),
+    /// The selected profile, as defined in a settings file.
+    Profile { name: String, path: PathBuf },
+    /// An environment variable.
+    Environment(String),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Default => f.write_str("default"),
+            Origin::File(path) => write!(f, "{}", path.display()),
+            Origin::Profile { name, path } => {
+                write!(f, "profile {} in {}", name, path.display())
+            }
+            Origin::Environment(var) => write!(f, "environment variable {}", var),
+        }
+    }
+}
+
+/// One settings file, parsed.
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub path: PathBuf,
+    pub document: Table,
+    /// The file as written.
+    pub contents: String,
+}
+
+impl Layer {
+    /// Read the settings file at `path`, or `None` if there isn't one.
+    pub async fn read(path: &Path) -> Result<Option<Layer>, ConfigError> {
+        match tokio::fs::read_to_string(path).await {
+            Ok(contents) => Layer::parse(path, &contents).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(source) => Err(ConfigError::Unreadable { path: path.to_owned(), source }),
+        }
+    }
+
+    /// Parse `contents`, in the format `path`'s extension names.
+    pub fn parse(path: &Path, contents: &str) -> Result<Layer, ConfigError> {
+        Ok(Layer {
+            path: path.to_owned(),
+            document: SettingsFormat::from_path(path).parse(path, contents)?,
+            contents: contents.to_owned(),
+        })
+    }
+}
+
+/// The system wide settings file, on platforms that have one.
+pub fn system_path() -> Option<PathBuf> {
+    #[cfg(unix)]
+    return Some(PathBuf::from(SYSTEM_SETTINGS));
+    #[cfg(not(unix))]
+    return None;
+}
+
+/// The nearest `.conf-sync.toml` in `start` or one of its parents.
+pub fn project_path(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_SETTINGS))
+        .find(|path| path.is_file())
+}
+
+/// Remove what a project's settings file isn't allowed to set from `layer`,
+/// with a warning for each setting removed. Synced files have to be inside
+/// the project, too, and are taken relative to the directory of the project's
+/// settings file rather than the current directory.
+pub fn restrict_project(layer: &mut Layer) -> Vec<Problem> {
+    let mut warnings = Vec::new();
+    retain_project_keys(&mut layer.document, "", &layer.path, &mut warnings);
+    let project_dir = layer.path.parent().unwrap_or_else(|| Path::new("")).to_owned();
+
+    let files = layer
+        .document
+        .get_mut("sync")
+        .and_then(|sync| sync.as_table_mut())
+        .and_then(|sync| sync.get_mut("files"))
+        .and_then(|files| files.as_array_mut());
+    if let Some(files) = files {
+        let mut kept = Vec::new();
+        for file in files.drain(..) {
+            match file.as_str() {
+                Some(file) if !is_inside_project(Path::new(file)) => warnings.push(Problem {
+                    key: "sync.files".into(),
+                    message: format!(
+                        "'{}' is outside the project, so {} can't sync it; ignoring it",
+                        file,
+                        layer.path.display(),
+                    ),
+                }),
+                Some(file) => {
+                    let relative: PathBuf = Path::new(file)
+                        .components()
+                        .filter(|component| *component != Component::CurDir)
+                        .collect();
+                    let file = project_dir.join(relative).to_string_lossy().into_owned();
+                    kept.push(toml::Value::String(file));
+                }
+                None => kept.push(file),
+            }
+        }
+        *files = kept;
+    }
+    warnings
+}
+
+fn retain_project_keys(table: &mut Table, prefix: &str, path: &Path, warnings: &mut Vec<Problem>) {
+    let keys: Vec<String> = table.keys().cloned().collect();
+    for key in keys {
+        let dotted = format!("{}{}", prefix, key);
+        let allowed = PROJECT_KEYS
+            .iter()
+            .any(|allowed| dotted == *allowed || dotted.starts_with(&format!("{}.", allowed)));
+        if allowed {
+            continue;
+        }
+
+        let holds_allowed = PROJECT_KEYS
+            .iter()
+            .any(|allowed| allowed.starts_with(&format!("{}.", dotted)));
+        if let Some(toml::Value::Table(nested)) = table.get_mut(&key).filter(|_| holds_allowed) {
+            retain_project_keys(nested, &format!("{}.", dotted), path, warnings);
+            continue;
+        }
+
+        table.remove(&key);
+        warnings.push(Problem {
+            key: dotted,
+            message: format!(
+                "can't be set in a project's settings, only in your own; ignoring it in {}",
+                path.display(),
+            ),
+        });
+    }
+}
+
+/// Whether `file`, taken relative to a directory in the project, stays
+/// inside it.
+fn is_inside_project(file: &Path) -> bool {
+    file.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Merge `layers` into one document, later layers overriding earlier ones key
+/// by key. Also returns the origin of every value set, by dotted key.
+pub fn merge(layers: &[Layer]) -> (Table, BTreeMap<String, Origin>) {
+    let mut merged = Table::new();
+    let mut origins = BTreeMap::new();
+    for layer in layers.iter() {
+        merge_table(&mut merged, &layer.document, "", &layer.path, &mut origins);
+    }
+    (merged, origins)
+}
+
+fn merge_table(
+    into: &mut Table,
+    from: &Table,
+    prefix: &str,
+    path: &Path,
+    origins: &mut BTreeMap<String, Origin>,
+) {
+    for (key, value) in from.iter() {
+        let dotted = format!("{}{}", prefix, key);
+        match (into.get_mut(key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(table)) => {
+                merge_table(existing, table, &format!("{}.", dotted), path, origins);
+            }
+            _ => {
+                // A value replacing a whole table drops what was set below it.
+                let nested = format!("{}.", dotted);
+                origins.retain(|key, _| !key.starts_with(&nested));
+                record_origins(value, &dotted, &Origin::File(path.to_owned()), origins);
+                into.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Record `origin` for every value in `value`, which is found at `dotted`.
+pub fn record_origins(
+    value: &toml::Value,
+    dotted: &str,
+    origin: &Origin,
+    origins: &mut BTreeMap<String, Origin>,
+) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter() {
+                record_origins(value, &format!("{}.{}", dotted, key), origin, origins);
+            }
+        }
+        _ => {
+            origins.insert(dotted.to_owned(), origin.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(path: &str, contents: &str) -> Layer {
+        Layer::parse(Path::new(path), contents).unwrap()
+    }
+
+    fn file(path: &str) -> Origin {
+        Origin::File(PathBuf::from(path))
+    }
+
+    #[test]
+    fn merge_records_the_layer_each_value_came_from() {
+        let system = layer(
+            "/etc/settings.toml",
+            "[gdrive]\napp_folder = \"system\"\nmode = \"folder\"\n[oauth]\nclient_id = \"system-id\"\n",
+        );
+        let user = layer("/home/settings.toml", "[gdrive]\napp_folder = \"user\"\n");
+        let project = layer("/project/.conf-sync.toml", "[sync]\nfiles = [\"a\"]\n");
+
+        let (merged, origins) = merge(&[system, user, project]);
+
+        assert_eq!(merged["gdrive"]["app_folder"].as_str(), Some("user"));
+        assert_eq!(merged["gdrive"]["mode"].as_str(), Some("folder"));
+        assert_eq!(origins["gdrive.app_folder"], file("/home/settings.toml"));
+        assert_eq!(origins["gdrive.mode"], file("/etc/settings.toml"));
+        assert_eq!(origins["oauth.client_id"], file("/etc/settings.toml"));
+        assert_eq!(origins["sync.files"], file("/project/.conf-sync.toml"));
+    }
+
+    #[test]
+    fn merge_forgets_origins_below_a_replaced_table() {
+        let system = layer("/etc/settings.toml", "[profiles.work.gdrive]\nmode = \"folder\"\n");
+        let user = layer("/home/settings.toml", "profiles = 1\n");
+
+        let (_, origins) = merge(&[system, user]);
+
+        assert_eq!(origins.get("profiles.work.gdrive.mode"), None);
+        assert_eq!(origins["profiles"], file("/home/settings.toml"));
+    }
+
+    #[test]
+    fn project_settings_keep_the_drive_folder_and_synced_files() {
+        let mut project = layer(
+            "/project/.conf-sync.toml",
+            "version = 1\n\
+             [gdrive]\nmode = \"folder\"\napp_folder = \"project\"\n\
+             [sync]\nfiles = [\"notes.md\", \"./config/app.toml\"]\nsettings = true\n",
+        );
+        let expected = project.document.clone();
+
+        let warnings = restrict_project(&mut project);
+
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(project.document["gdrive"], expected["gdrive"]);
+        assert_eq!(project.document["sync"]["settings"], expected["sync"]["settings"]);
+    }
+
+    #[test]
+    fn project_files_are_relative_to_the_project() {
+        let mut project = layer(
+            "/project/.conf-sync.toml",
+            "[sync]\nfiles = [\"notes.md\", \"./config/app.toml\"]\n",
+        );
+
+        restrict_project(&mut project);
+
+        let files = project.document["sync"]["files"].as_array().unwrap();
+        assert_eq!(files, &vec![
+            toml::Value::String("/project/notes.md".into()),
+            toml::Value::String("/project/config/app.toml".into()),
+        ]);
+    }
+
+    #[test]
+    fn project_settings_cannot_change_the_client_tokens_or_profiles() {
+        let mut project = layer(
+            "/project/.conf-sync.toml",
+            "profile = \"work\"\n\
+             [gdrive]\nmode = \"folder\"\n\
+             [oauth]\ntoken_uri = \"https://attacker.example/token\"\nclient_id = \"attacker\"\n\
+             [tokens]\nstorage = \"file\"\npath = \"/tmp/tokens.json\"\n\
+             [profiles.work.oauth]\nauth_uri = \"https://attacker.example/auth\"\n",
+        );
+
+        let warnings = restrict_project(&mut project);
+
+        let keys: Vec<&str> = warnings.iter().map(|problem| problem.key.as_str()).collect();
+        assert_eq!(keys, ["oauth", "profile", "profiles", "tokens"]);
+        let remaining: Vec<&String> = project.document.keys().collect();
+        assert_eq!(remaining, ["gdrive"]);
+        assert_eq!(project.document["gdrive"]["mode"].as_str(), Some("folder"));
+    }
+
+    #[test]
+    fn project_settings_cannot_sync_files_outside_the_project() {
+        let mut project = layer(
+            "/project/.conf-sync.toml",
+            "[sync]\nfiles = [\"notes.md\", \"/home/user/.ssh/id_rsa\", \"../../.netrc\"]\n",
+        );
+
+        let warnings = restrict_project(&mut project);
+
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings.iter().all(|problem| problem.key == "sync.files"));
+        let files = project.document["sync"]["files"].as_array().unwrap();
+        assert_eq!(files, &vec![toml::Value::String("/project/notes.md".into())]);
+    }
+
+    #[test]
+    fn project_settings_cannot_set_other_drive_settings() {
+        let mut project = layer("/project/.conf-sync.toml", "[gdrive]\nmode = \"folder\"\nother = 1\n");
+
+        let warnings = restrict_project(&mut project);
+
+        let keys: Vec<&str> = warnings.iter().map(|problem| problem.key.as_str()).collect();
+        assert_eq!(keys, ["gdrive.other"]);
+        assert_eq!(project.document["gdrive"].as_table().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,106 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use serde_derive::Deserialize;
+use directories::ProjectDirs;
+use crate::scopes;
+use super::layers::Layer;
+use super::{write_settings, ConfigError, OauthConfig, Settings, APP_NAME, SETTINGS_VERSION};
+
+/// The client configuration older versions of conf-sync kept in a separate
+/// file, written through confy.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct LegacyClientConfig {
+    client_id: String,
+    client_secret: String,
+    project_id: String,
+    auth_uri: String,
+    token_uri: String,
+    auth_provider_x509_cert_url: String,
+    redirect_uris: Vec<String>,
+    scopes: Vec<String>,
+}
+
+/// Where confy kept the legacy configuration: `conf-sync.toml` in the
+/// project's config directory.
+fn legacy_path() -> Option<PathBuf> {
+    let project = ProjectDirs::from("rs", APP_NAME, APP_NAME)?;
+    Some(project.config_dir().join(format!("{}.toml", APP_NAME)))
+}
+
+/// Whether `layer` only holds the default settings. Older versions of
+/// conf-sync wrote them out on first run, next to the legacy file, so such a
+/// settings file doesn't keep the legacy one from being migrated.
+pub(super) fn is_untouched(layer: &Layer) -> bool {
+    let mut settings: Settings = match toml::Value::Table(layer.document.clone()).try_into() {
+        Ok(settings) => settings,
+        Err(_) => return false,
+    };
+    // Files written before settings were versioned have no version.
+    settings.version = SETTINGS_VERSION;
+    match (toml::Value::try_from(&settings), toml::Value::try_from(Settings::default())) {
+        (Ok(settings), Ok(defaults)) => settings == defaults,
+        _ => false,
+    }
+}
+
+/// Turn a legacy configuration file into `conf_path`, if there is one. The
+/// legacy file is renamed afterwards so it isn't migrated again. Returns what
+/// was written to `conf_path`, which is replaced if it exists.
+pub(super) async fn migrate(conf_path: &Path) -> Result<Option<String>, ConfigError> {
+    let legacy_path = match legacy_path() {
+        Some(legacy_path) if legacy_path != conf_path => legacy_path,
+        _ => return Ok(None),
+    };
+    let contents = match tokio::fs::read_to_string(&legacy_path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(source) => return Err(ConfigError::Unreadable { path: legacy_path, source }),
+    };
+    let legacy: LegacyClientConfig = toml::from_str(&contents)
+        .map_err(|e| ConfigError::syntax(legacy_path.clone(), e))?;
+
+    write_settings(conf_path, &into_settings(legacy)).await?;
+
+    let mut migrated_name = legacy_path.as_os_str().to_owned();
+    migrated_name.push(".migrated");
+    tokio::fs::rename(&legacy_path, &migrated_name)
+        .await
+        .map_err(|source| ConfigError::Unwritable { path: legacy_path.clone(), source })?;
+    eprintln!(
+        "Moved settings from {} to {}",
+        legacy_path.display(),
+        conf_path.display(),
+    );
+
+    tokio::fs::read_to_string(conf_path)
+        .await
+        .map(Some)
+        .map_err(|source| ConfigError::Unreadable { path: conf_path.to_owned(), source })
+}
+
+fn into_settings(legacy: LegacyClientConfig) -> Settings {
+    // The legacy file listed every scope to request, starting from the old
+    // defaults. Scopes conf-sync's own features ask for are requested when
+    // needed now, so only the scopes the user added are kept as extra scopes.
+    let extra_scopes = legacy
+        .scopes
+        .into_iter()
+        .filter(|scope| !scopes::was_default_scope(scope))
+        .collect();
+
+    Settings {
+        oauth: OauthConfig {
+            client_id: legacy.client_id,
+            client_secret: legacy.client_secret,
+            project_id: legacy.project_id,
+            auth_uri: legacy.auth_uri,
+            token_uri: legacy.token_uri,
+            auth_provider_x509_cert_url: legacy.auth_provider_x509_cert_url,
+            redirect_uris: legacy.redirect_uris,
+            scopes: extra_scopes,
+            ..OauthConfig::default()
+        },
+        ..Settings::default()
+    }
+}
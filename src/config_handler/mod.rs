@@ -0,0 +1,1071 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde_derive::{ Deserialize, Serialize };
+use yup_oauth2::{ ApplicationSecret };
+use config::{Config, Environment, File, FileFormat};
+use toml::value::Table;
+use directories::BaseDirs;
+use crate::fs::{ensure_dir, file_exists, secure_remove_file, write_file_atomic, FileLock};
+use crate::scopes::{self, DriveMode, Feature};
+use crate::token_storage::{Keychain, TokenBackend, TokenStore};
+
+mod document;
+mod error;
+mod format;
+mod layers;
+mod legacy;
+mod schema;
+mod shared;
+mod validate;
+
+pub use error::ConfigError;
+pub use format::SettingsFormat;
+pub use layers::Origin;
+pub use shared::SHARED_SETTINGS_FILE;
+pub use validate::Problem;
+use document::SettingsDocument;
+use layers::Layer;
+use schema::SETTINGS_VERSION;
+
+const APP_NAME: &str = "conf-sync";
+/// Prefix of the environment variables that override settings.
+const ENV_PREFIX: &str = "CONF_SYNC";
+/// Separates the section from the key in those variables.
+const ENV_SEPARATOR: &str = "__";
+/// The `oauth` settings that describe the client itself.
+const OAUTH_CLIENT_KEYS: [&str; 7] = [
+    "client_id",
+    "client_secret",
+    "project_id",
+    "auth_uri",
+    "token_uri",
+    "auth_provider_x509_cert_url",
+    "redirect_uris",
+];
+/// Shown in place of the client secret.
+const SECRET_MASK: &str = "********";
+/// The client secret belongs in the keychain, so `config get` and `config set`
+/// don't offer it.
+const CLIENT_SECRET_SETTING: &str = "oauth.client_secret";
+/// Where `conf-sync auth import-client` keeps the client secret, in the data
+/// directory, when the keychain can't be reached.
+const CLIENT_SECRET_FILE: &str = "client_secret.json";
+/// The sections a profile can override.
+const PROFILE_SECTIONS: [&str; 3] = ["gdrive", "oauth", "sync"];
+/// Each profile keeps its tokens in a directory of its own under this one in
+/// the data directory.
+const PROFILES_DIR: &str = "profiles";
+const DEFAULT_AUTH_TIMEOUT: u64 = 300;
+
+/// Missing fields take their defaults, so the client can be described
+/// across several settings files; `validate` reports what is still missing.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+#[allow(unused)]
+pub struct OauthConfig {
+    pub client_id: String,
+    /// Left empty when the secret was imported with `conf-sync auth
+    /// import-client`. Hosts without a keychain can give it in the
+    /// `CONF_SYNC_OAUTH__CLIENT_SECRET` environment variable instead.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub client_secret: String,
+    pub project_id: String,
+    pub auth_uri: String,
+    pub token_uri: String,
+    pub auth_provider_x509_cert_url: String,
+    pub redirect_uris: Vec<String>,
+    /// Scopes to request on top of the ones derived from enabled features.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Request the account's email so it can be shown to the user.
+    #[serde(default)]
+    pub show_account: bool,
+    /// Seconds to wait for the user to finish signing in before giving up.
+    #[serde(default = "default_auth_timeout")]
+    pub auth_timeout: u64,
+}
+
+fn default_auth_timeout() -> u64 {
+    DEFAULT_AUTH_TIMEOUT
+}
+
+impl ::std::default::Default for OauthConfig {
+    fn default() -> Self {
+        Self {
+            client_id: "".into(),
+            client_secret: "".into(),
+            project_id: "".into(),
+            auth_uri: "".into(),
+            token_uri: "".into(),
+            auth_provider_x509_cert_url: "".into(),
+            redirect_uris: [].to_vec(),
+            scopes: [].to_vec(),
+            show_account: false,
+            auth_timeout: DEFAULT_AUTH_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+#[allow(unused)]
+pub struct DriveConfig {
+    /// The folder synced files are kept in, when `mode` is `folder`.
+    pub app_folder: String,
+    #[serde(default)]
+    pub mode: DriveMode,
+}
+
+impl ::std::default::Default for DriveConfig {
+    fn default() -> Self {
+        Self {
+            app_folder: APP_NAME.into(),
+            mode: DriveMode::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Default)]
+pub struct TokenConfig {
+    #[serde(default)]
+    pub storage: TokenBackend,
+    /// Token file used by the file based backends. Defaults to
+    /// `keystorage.json`, or `keystorage.enc` when encrypted, in the data
+    /// directory.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Default)]
+pub struct SyncConfig {
+    /// Files kept in sync with the remote folder.
+    #[serde(default)]
+    pub files: Vec<PathBuf>,
+    /// Also push these settings, for `conf-sync pull` to bring them to other
+    /// machines. The client secret and token settings aren't shared.
+    #[serde(default)]
+    pub settings: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Settings {
+    /// The layout the settings are in, upgraded on load when it is older
+    /// than this version of conf-sync's.
+    #[serde(default)]
+    pub version: u32,
+    /// The profile used when none is selected with `--profile`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub gdrive: DriveConfig,
+    #[serde(default)]
+    oauth: OauthConfig,
+    #[serde(default)]
+    pub sync: SyncConfig,
+    #[serde(default)]
+    pub tokens: TokenConfig,
+    /// Named sets of `gdrive`, `oauth` and `sync` settings, layered over the
+    /// ones above when the profile is selected.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, toml::value::Table>,
+}
+
+impl ::std::default::Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            profile: None,
+            gdrive: DriveConfig::default(),
+            oauth: OauthConfig::default(),
+            sync: SyncConfig::default(),
+            tokens: TokenConfig::default(),
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BasePaths {
+    pub conf_dir: PathBuf,
+    pub conf_path: PathBuf,
+    pub data_dir: PathBuf,
+}
+
+/// A client secret kept in `CLIENT_SECRET_FILE`.
+#[derive(Serialize, Deserialize)]
+struct StoredClientSecret {
+    client_id: String,
+    client_secret: String,
+}
+
+#[derive(Clone)]
+pub struct ConfigHandler {
+    pub app_name: String,
+    /// The selected profile, if any.
+    pub profile: Option<String>,
+    pub settings: Settings,
+    pub oauth_config: OauthConfig,
+    pub base_paths: BasePaths,
+    /// The settings file given with `--config`, if any.
+    explicit_path: Option<PathBuf>,
+    /// The profile given with `--profile`, if any.
+    explicit_profile: Option<String>,
+    /// The user's settings file as written, without the other layers, so
+    /// saving only writes what the user set, comments and all.
+    document: SettingsDocument,
+    /// Where each setting in effect came from, by dotted key.
+    origins: BTreeMap<String, Origin>,
+    /// The profiles defined in any of the settings files.
+    profile_names: Vec<String>,
+    /// Settings a project's settings file wasn't allowed to set, which were
+    /// left out.
+    warnings: Vec<Problem>,
+}
+
+impl ConfigHandler {
+    /// Load the settings, merging `/etc/conf-sync/settings.toml`, the user's
+    /// settings file and the nearest `.conf-sync.toml` above the current
+    /// directory, in that order. The project's file may only set the drive
+    /// folder and what is synced. The user's file is `conf_path`, or
+    /// `settings.toml` in the config directory when no path is given; a
+    /// default one is created if no settings file exists at all. `profile`
+    /// selects a profile other than the one named in the files.
+    pub async fn new(
+        conf_path: Option<PathBuf>,
+        profile: Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let explicit_path = conf_path.clone();
+        let explicit_profile = profile.clone();
+        let conf_path = match conf_path {
+            Some(conf_path) => conf_path,
+            None => settings_path().await?,
+        };
+
+        let user = match Layer::read(&conf_path).await? {
+            Some(layer) if explicit_path.is_none() && legacy::is_untouched(&layer) => {
+                match legacy::migrate(&conf_path).await? {
+                    Some(contents) => Some(Layer::parse(&conf_path, &contents)?),
+                    None => Some(layer),
+                }
+            }
+            Some(layer) => Some(layer),
+            None if explicit_path.is_some() => {
+                return Err(ConfigError::Unreadable {
+                    path: conf_path,
+                    source: io::ErrorKind::NotFound.into(),
+                });
+            }
+            None => match legacy::migrate(&conf_path).await? {
+                Some(contents) => Some(Layer::parse(&conf_path, &contents)?),
+                None => None,
+            },
+        };
+        let mut system = match layers::system_path() {
+            Some(path) => Layer::read(&path).await?,
+            None => None,
+        };
+        let mut project = match std::env::current_dir().ok().as_deref().and_then(layers::project_path) {
+            Some(path) => Layer::read(&path).await?,
+            None => None,
+        };
+
+        // Only the user's own file is upgraded on disk; the others are shared
+        // and upgraded as they are read.
+        for layer in system.iter_mut().chain(project.iter_mut()) {
+            schema::upgrade(layer)?;
+        }
+        let warnings = match project.as_mut() {
+            Some(project) => layers::restrict_project(project),
+            None => Vec::new(),
+        };
+
+        // The defaults are only written out when nothing configures conf-sync
+        // yet, so they don't hide the system or project settings.
+        let user = match user {
+            None if system.is_none() && project.is_none() => {
+                let contents = init_default_config(&conf_path).await?;
+                Some(Layer::parse(&conf_path, &contents)?)
+            }
+            Some(mut user) => {
+                schema::upgrade_file(&mut user).await?;
+                Some(user)
+            }
+            None => None,
+        };
+        let document = match &user {
+            Some(user) => SettingsDocument::parse(&user.path, &user.contents)?,
+            None => SettingsDocument::default(),
+        };
+        let layers: Vec<Layer> = system.into_iter().chain(user).chain(project).collect();
+        let (merged, mut origins) = layers::merge(&layers);
+
+        let profile = profile.or_else(|| {
+            merged.get("profile").and_then(toml::Value::as_str).map(String::from)
+        });
+        let profile_table = match &profile {
+            Some(name) => Some(profile_settings(&conf_path, &merged, name)?),
+            None => None,
+        };
+        if let Some(name) = &profile {
+            let prefix = format!("profiles.{}.", name);
+            let overridden: Vec<(String, Origin)> = origins
+                .iter()
+                .filter_map(|(key, origin)| match origin {
+                    Origin::File(path) => Some((
+                        key.strip_prefix(&prefix)?.to_owned(),
+                        Origin::Profile { name: name.clone(), path: path.clone() },
+                    )),
+                    _ => None,
+                })
+                .collect();
+            origins.extend(overridden);
+        }
+        let env_vars = env_overrides();
+        for env_var in env_vars.iter() {
+            origins.insert(env_var.key(), Origin::Environment(env_var.var.clone()));
+        }
+
+        let parse = |env_vars: &[EnvOverride]| {
+            parse_settings(&conf_path, &merged, profile_table.as_ref(), Some(environment(env_vars)))
+        };
+        let settings = match parse(&env_vars) {
+            Ok(settings) => settings,
+            Err(e) => {
+                // When the settings files are fine by themselves, the error
+                // is down to one of the variables; name it rather than the
+                // user's settings file.
+                parse(&[])?;
+                let env_var = env_vars.iter().find(|env_var| parse(std::slice::from_ref(*env_var)).is_err());
+                return Err(match (env_var, e) {
+                    (Some(env_var), ConfigError::Schema { message, .. }) => ConfigError::Environment {
+                        var: env_var.var.clone(),
+                        message,
+                    },
+                    (_, e) => e,
+                });
+            }
+        };
+
+        // A profile's directory is created along with its first token file.
+        let mut data_dir = data_path().await?;
+        if let Some(profile) = &profile {
+            data_dir = data_dir.join(PROFILES_DIR).join(profile);
+        }
+
+        let profile_names = merged
+            .get("profiles")
+            .and_then(toml::Value::as_table)
+            .map(|profiles| profiles.keys().cloned().collect())
+            .unwrap_or_default();
+
+        Ok(ConfigHandler {
+            app_name: APP_NAME.into(),
+            profile,
+            oauth_config: settings.oauth.clone(),
+            settings,
+            explicit_path,
+            explicit_profile,
+            document,
+            origins,
+            profile_names,
+            warnings,
+            base_paths: BasePaths {
+                conf_dir: PathBuf::from(conf_path.parent().unwrap()),
+                conf_path,
+                data_dir,
+            }
+        })
+    }
+
+    /// Load the same settings files again, with `profile` selected.
+    pub async fn with_profile(&self, profile: String) -> Result<Self, ConfigError> {
+        ConfigHandler::new(self.explicit_path.clone(), Some(profile)).await
+    }
+
+    /// Write the default settings in `format` to `conf_path`, or to the
+    /// settings file of that format in the config directory. Fails if the
+    /// settings file is already there. Returns the file written.
+    pub async fn init(
+        conf_path: Option<PathBuf>,
+        format: SettingsFormat,
+    ) -> Result<PathBuf, ConfigError> {
+        let existing = match &conf_path {
+            Some(conf_path) if file_exists(conf_path).await => Some(conf_path.clone()),
+            Some(_) => None,
+            None => existing_settings_path().await?,
+        };
+        if let Some(path) = existing {
+            return Err(ConfigError::AlreadyExists { path });
+        }
+
+        let conf_path = match conf_path {
+            Some(conf_path) => conf_path,
+            None => settings_dir().await?.join(settings_file_name(format)),
+        };
+        write_settings(&conf_path, &Settings::default()).await?;
+        Ok(conf_path)
+    }
+
+    /// Load the settings files again, with the same `--config` and
+    /// `--profile`, to pick up changes made to them.
+    pub async fn reload(&self) -> Result<Self, ConfigError> {
+        ConfigHandler::new(self.explicit_path.clone(), self.explicit_profile.clone()).await
+    }
+
+    /// The profiles defined in the settings files.
+    pub fn profile_names(&self) -> Vec<String> {
+        self.profile_names.clone()
+    }
+
+    /// Make `profile` the one used when none is selected, or go back to the
+    /// settings without a profile. Call `save` to persist it.
+    pub fn set_default_profile(&mut self, profile: Option<String>) -> Result<(), ConfigError> {
+        match profile {
+            Some(name) if !self.profile_names.contains(&name) => {
+                return Err(self.unknown_profile(&name));
+            }
+            Some(name) => {
+                self.document
+                    .set(&["profile"], name.into())
+                    .map_err(|key| self.not_a_table(&key))?;
+            }
+            None => {
+                self.document.remove(&["profile"]);
+            }
+        }
+        Ok(())
+    }
+
+    /// The value in effect for the dotted `key`, masked like
+    /// `settings_toml`. The client secret can't be read this way.
+    pub fn get_value(&self, key: &str) -> Result<toml::Value, ConfigError> {
+        if !is_setting(key) {
+            return Err(ConfigError::UnknownSetting { key: key.into() });
+        }
+        if key == CLIENT_SECRET_SETTING {
+            return Err(ConfigError::SecretSetting { key: key.into() });
+        }
+        let values = self.settings_values()?;
+        match values.into_iter().find(|(dotted, _)| dotted == key) {
+            Some((_, value)) => Ok(value),
+            // Only unset options are left out of the settings.
+            None => Err(ConfigError::Schema {
+                path: self.base_paths.conf_path.clone(),
+                message: format!("{} is not set", key),
+            }),
+        }
+    }
+
+    /// Set the dotted `key` in the user's settings file, e.g.
+    /// `gdrive.mode` or `profiles.work.gdrive.mode`. `value` is read as a
+    /// TOML value, or taken as a string if it isn't one or the setting is a
+    /// string. The client secret can't be set this way. Call `save` to
+    /// persist it; the settings in effect aren't updated until they are
+    /// loaded again.
+    pub fn set_value(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let setting = setting_key(key).ok_or_else(|| ConfigError::UnknownSetting { key: key.into() })?;
+        if setting == CLIENT_SECRET_SETTING {
+            return Err(ConfigError::SecretSetting { key: key.into() });
+        }
+        let is_string = setting == "profile"
+            || setting_defaults().iter().any(|(dotted, default)| {
+                dotted == setting && matches!(default, toml::Value::String(_))
+            });
+        let value = match value.parse::<toml_edit::Value>() {
+            Ok(parsed) if !is_string || parsed.is_str() => parsed,
+            _ => value.into(),
+        };
+
+        let keys: Vec<&str> = key.split('.').collect();
+        self.document.set(&keys, value).map_err(|key| self.not_a_table(&key))
+    }
+
+    fn not_a_table(&self, key: &str) -> ConfigError {
+        ConfigError::Schema {
+            path: self.base_paths.conf_path.clone(),
+            message: format!("{} must be a table", key),
+        }
+    }
+
+    /// Where the setting at the dotted `key` got its value.
+    pub fn origin(&self, key: &str) -> Origin {
+        self.origins.get(key).cloned().unwrap_or(Origin::Default)
+    }
+
+    /// The settings in effect as TOML, for display. The client secret is
+    /// masked and profile definitions are left out.
+    pub fn settings_toml(&self) -> Result<String, ConfigError> {
+        table_to_string(&self.display_settings()?).map_err(|e| ConfigError::Schema {
+            path: self.base_paths.conf_path.clone(),
+            message: e.to_string(),
+        })
+    }
+
+    /// Every setting in effect by dotted key, masked like `settings_toml`.
+    pub fn settings_values(&self) -> Result<Vec<(String, toml::Value)>, ConfigError> {
+        Ok(flatten(&self.display_settings()?))
+    }
+
+    fn display_settings(&self) -> Result<Table, ConfigError> {
+        let mut settings = self.settings.clone();
+        if !settings.oauth.client_secret.is_empty() {
+            settings.oauth.client_secret = SECRET_MASK.into();
+        }
+        settings.profile = None;
+        settings.profiles.clear();
+        match toml::Value::try_from(&settings) {
+            Ok(toml::Value::Table(mut table)) => {
+                table.remove("version");
+                Ok(table)
+            }
+            Ok(_) => unreachable!("settings always serialize to a table"),
+            Err(e) => Err(ConfigError::Schema {
+                path: self.base_paths.conf_path.clone(),
+                message: e.to_string(),
+            }),
+        }
+    }
+
+    fn unknown_profile(&self, name: &str) -> ConfigError {
+        ConfigError::UnknownProfile {
+            path: self.base_paths.conf_path.clone(),
+            name: name.into(),
+            known: self.profile_names(),
+        }
+    }
+
+    /// Every problem with the settings in effect. Problems with a value the
+    /// user's settings file didn't set say where it came from.
+    pub fn problems(&self) -> Vec<Problem> {
+        let mut problems = validate::validate(&self.settings);
+        for problem in problems.iter_mut() {
+            match self.origin(&problem.key) {
+                Origin::Default => {},
+                Origin::File(path) if path == self.base_paths.conf_path => {},
+                origin => problem.message = format!("{} (set by {})", problem.message, origin),
+            }
+        }
+        problems
+    }
+
+    /// Settings that were ignored while loading, because a project's
+    /// settings file isn't allowed to set them.
+    pub fn warnings(&self) -> &[Problem] {
+        &self.warnings
+    }
+
+    /// Check the settings in effect, reporting every problem found.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(ConfigError::Invalid {
+            path: self.base_paths.conf_path.clone(),
+            problems,
+        })
+    }
+
+    /// The feature backing file sync, depending on the configured drive mode.
+    pub fn drive_feature(&self) -> Feature {
+        Feature::from(self.settings.gdrive.mode)
+    }
+
+    /// The features enabled by the current settings.
+    pub fn features(&self) -> Vec<Feature> {
+        let mut features = vec![self.drive_feature()];
+        if self.oauth_config.show_account {
+            features.push(Feature::AccountDisplay);
+        }
+        features
+    }
+
+    /// Every scope the enabled features could need.
+    pub fn required_scopes(&self) -> Vec<String> {
+        scopes::scopes_for(&self.features(), &self.oauth_config.scopes)
+    }
+
+    /// The scopes to request when `feature` is used. Tokens are requested per
+    /// feature so a new scope is only asked for once something needs it.
+    pub fn feature_scopes(&self, feature: Feature) -> Vec<String> {
+        scopes::scopes_for(&[feature], &self.oauth_config.scopes)
+    }
+
+    /// Open the token storage selected in the settings.
+    pub async fn open_token_store(&self) -> io::Result<TokenStore> {
+        let backend = self.settings.tokens.storage;
+        TokenStore::open(backend, self.token_path(backend), self.keychain()).await
+    }
+
+    /// Open the token storage selected in the settings only to look at the
+    /// tokens, leaving it as it is.
+    pub async fn inspect_token_store(&self) -> io::Result<TokenStore> {
+        let backend = self.settings.tokens.storage;
+        TokenStore::open_read_only(backend, self.token_path(backend), self.keychain()).await
+    }
+
+    /// The keychain this profile keeps its tokens in.
+    pub fn keychain(&self) -> Keychain {
+        Keychain::for_profile(self.profile.as_deref())
+    }
+
+    /// The token file `backend` uses. The configured path only applies to the
+    /// configured backend; the others use their default file name.
+    pub fn token_path(&self, backend: TokenBackend) -> PathBuf {
+        match &self.settings.tokens.path {
+            Some(path) if backend == self.settings.tokens.storage => path.clone(),
+            _ => self.base_paths.data_dir.join(backend.default_filename()),
+        }
+    }
+
+    pub fn auth_timeout(&self) -> Duration {
+        Duration::from_secs(self.oauth_config.auth_timeout)
+    }
+
+    /// Use the OAuth client in `oauth_config` for the selected profile, or
+    /// for the settings without one. Only the client's details are written;
+    /// scopes and the other options stay as they are configured. Call `save`
+    /// to persist it.
+    pub fn set_oauth_config(&mut self, oauth_config: OauthConfig) -> Result<(), ConfigError> {
+        let schema_error = |message: String| ConfigError::Schema {
+            path: self.base_paths.conf_path.clone(),
+            message,
+        };
+        let client = match toml::Value::try_from(&oauth_config) {
+            Ok(toml::Value::Table(client)) => client,
+            Ok(_) => return Err(schema_error("oauth must be a table".into())),
+            Err(e) => return Err(schema_error(e.to_string())),
+        };
+
+        let mut keys = Vec::new();
+        if let Some(name) = &self.profile {
+            keys.extend(["profiles", name.as_str()]);
+        }
+        keys.push("oauth");
+        if oauth_config.client_secret.is_empty() {
+            let keys: Vec<&str> = keys.iter().copied().chain(["client_secret"]).collect();
+            self.document.remove(&keys);
+        }
+        for (key, value) in client.iter() {
+            if !OAUTH_CLIENT_KEYS.contains(&key.as_str()) {
+                continue;
+            }
+            let keys: Vec<&str> = keys.iter().copied().chain([key.as_str()]).collect();
+            self.document.set_toml(&keys, value).map_err(|key| self.not_a_table(&key))?;
+        }
+
+        self.settings.oauth = oauth_config.clone();
+        self.oauth_config = oauth_config;
+        Ok(())
+    }
+
+    /// Wait until no other conf-sync process is changing the user's settings
+    /// file, and keep them from doing so while the returned lock is held. The
+    /// file is read again, so changes made to it since the settings were
+    /// loaded aren't lost on `save`. Take it before changing the settings and
+    /// hold it until they are saved.
+    pub async fn lock_settings(&mut self) -> Result<FileLock, ConfigError> {
+        let conf_path = &self.base_paths.conf_path;
+        let lock = FileLock::exclusive(conf_path)
+            .await
+            .map_err(|source| ConfigError::Unwritable { path: conf_path.clone(), source })?;
+        match tokio::fs::read_to_string(conf_path).await {
+            Ok(contents) => self.document = SettingsDocument::parse(conf_path, &contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(source) => return Err(ConfigError::Unreadable { path: conf_path.clone(), source }),
+        }
+        Ok(lock)
+    }
+
+    /// Write the user's settings back to their settings file.
+    pub async fn save(&self) -> Result<(), ConfigError> {
+        let contents = self.document.contents().map_err(|e| ConfigError::Unwritable {
+            path: self.base_paths.conf_path.clone(),
+            source: io::Error::new(io::ErrorKind::InvalidData, e),
+        })?;
+        write_document(&self.base_paths.conf_path, &contents).await
+    }
+
+    /// The user's settings to share with other machines, as TOML. Settings
+    /// from the other layers and those that only apply to this machine are
+    /// left out.
+    pub fn shared_settings(&self) -> Result<String, ConfigError> {
+        let schema_error = |message: String| ConfigError::Schema {
+            path: self.base_paths.conf_path.clone(),
+            message,
+        };
+        let mut document = self.document.to_table().map_err(schema_error)?;
+        shared::sanitize(&mut document);
+        table_to_string(&document).map_err(|e| schema_error(e.to_string()))
+    }
+
+    /// Merge settings shared from another machine into the user's settings
+    /// file, returning the dotted keys that changed. Shared values win; the
+    /// rest are kept. Call `save` to write the file; the settings in effect
+    /// only change once they are loaded again.
+    pub fn merge_shared(&mut self, contents: &str) -> Result<Vec<String>, ConfigError> {
+        let mut layer = Layer::parse(Path::new(SHARED_SETTINGS_FILE), contents)?;
+        schema::upgrade(&mut layer)?;
+        let mut shared = layer.document;
+        shared::sanitize(&mut shared);
+        // The user's file is already upgraded to this version.
+        shared.remove("version");
+
+        shared::merge(&mut self.document, &shared).map_err(|key| self.not_a_table(&key))
+    }
+
+    /// The OAuth client to sign in with. Its secret is taken from the
+    /// settings or the environment when they give it, otherwise from where
+    /// `store_client_secret` put it.
+    pub fn get_app_secret(&self) -> Result<ApplicationSecret, ConfigError> {
+        let cfg = self.oauth_config.clone();
+        let client_secret = if !cfg.client_secret.is_empty() {
+            cfg.client_secret
+        } else if let Some(client_secret) = self.read_client_secret_file(&cfg.client_id) {
+            client_secret
+        } else {
+            match Keychain::new().get_client_secret(&cfg.client_id) {
+                Ok(Some(client_secret)) => client_secret,
+                Ok(None) => {
+                    return Err(ConfigError::MissingClientSecret {
+                        client_id: cfg.client_id,
+                        reason: None,
+                    });
+                }
+                Err(e) => {
+                    return Err(ConfigError::MissingClientSecret {
+                        client_id: cfg.client_id,
+                        reason: Some(e.to_string()),
+                    });
+                }
+            }
+        };
+        Ok(ApplicationSecret {
+            client_id: cfg.client_id,
+            client_secret,
+            project_id: Some(cfg.project_id),
+            // Ask Google to fold previously granted scopes into each new
+            // token, so requesting a feature's scopes doesn't drop the others.
+            auth_uri: with_granted_scopes(&cfg.auth_uri),
+            token_uri: cfg.token_uri,
+            redirect_uris: cfg.redirect_uris,
+            auth_provider_x509_cert_url: Some(cfg.auth_provider_x509_cert_url),
+            client_email: Some("".into()),
+            client_x509_cert_url: Some("".into()),
+        })
+    }
+
+    /// The file the client secret is kept in when the keychain can't be
+    /// reached.
+    pub fn client_secret_path(&self) -> PathBuf {
+        self.base_paths.data_dir.join(CLIENT_SECRET_FILE)
+    }
+
+    /// Store the secret of OAuth client `client_id` in the keychain, or in
+    /// `client_secret_path` when the keychain can't be reached. Returns where
+    /// it was stored, for display.
+    pub async fn store_client_secret(
+        &self,
+        client_id: &str,
+        client_secret: &str,
+    ) -> Result<String, ConfigError> {
+        let path = self.client_secret_path();
+        match Keychain::new().set_client_secret(client_id, client_secret) {
+            Ok(()) => {
+                // A secret saved while the keychain was unavailable would
+                // otherwise be read in preference to this one.
+                if file_exists(&path).await {
+                    secure_remove_file(&path)
+                        .await
+                        .map_err(|source| ConfigError::Unwritable { path: path.clone(), source })?;
+                }
+                Ok("the keychain".into())
+            }
+            Err(e) => {
+                eprintln!("The keychain is unavailable ({}); storing the client secret in {}", e, path.display());
+                let stored = StoredClientSecret {
+                    client_id: client_id.into(),
+                    client_secret: client_secret.into(),
+                };
+                let json = serde_json::to_string(&stored).expect("client secrets always serialize");
+                write_file_atomic(&path, json.as_bytes())
+                    .await
+                    .map_err(|source| ConfigError::Unwritable { path: path.clone(), source })?;
+                Ok(path.display().to_string())
+            }
+        }
+    }
+
+    /// The secret of `client_id` saved in `client_secret_path`, if any. A
+    /// file for another client is ignored.
+    fn read_client_secret_file(&self, client_id: &str) -> Option<String> {
+        let contents = std::fs::read_to_string(self.client_secret_path()).ok()?;
+        let stored: StoredClientSecret = serde_json::from_str(&contents).ok()?;
+        (stored.client_id == client_id).then_some(stored.client_secret)
+    }
+}
+
+fn with_granted_scopes(auth_uri: &str) -> String {
+    if auth_uri.is_empty() || auth_uri.contains("include_granted_scopes=") {
+        return auth_uri.into();
+    }
+    let separator = if auth_uri.contains('?') { "&" } else { "?" };
+    format!("{}{}include_granted_scopes=true", auth_uri, separator)
+}
+
+/// Turn the merged settings `document` into `Settings`, with `profile` and
+/// then `overrides` layered on top.
+fn parse_settings(
+    conf_path: &Path,
+    document: &Table,
+    profile: Option<&Table>,
+    overrides: Option<Environment>,
+) -> Result<Settings, ConfigError> {
+    let schema_error = |message: String| ConfigError::Schema {
+        path: conf_path.to_owned(),
+        message,
+    };
+    let to_toml = |table: &Table| table_to_string(table).map_err(|e| schema_error(e.to_string()));
+
+    let mut builder = Config::builder()
+        .add_source(File::from_str(&to_toml(document)?, FileFormat::Toml));
+    if let Some(profile) = profile {
+        builder = builder.add_source(File::from_str(&to_toml(profile)?, FileFormat::Toml));
+    }
+    if let Some(overrides) = overrides {
+        builder = builder.add_source(overrides);
+    }
+    builder
+        .build()
+        .map_err(|e| schema_error(e.to_string()))?
+        .try_deserialize()
+        .map_err(|e| schema_error(e.to_string()))
+}
+
+/// The settings profile `name` overrides.
+fn profile_settings(
+    conf_path: &Path,
+    document: &Table,
+    name: &str,
+) -> Result<Table, ConfigError> {
+    let schema_error = |message: String| ConfigError::Schema {
+        path: conf_path.to_owned(),
+        message,
+    };
+    // The name becomes the directory the profile's tokens are kept in.
+    validate::check_folder_name(name)
+        .map_err(|message| schema_error(format!("profile name {}", message)))?;
+
+    let profiles = document.get("profiles").and_then(toml::Value::as_table);
+    let profile = match profiles.and_then(|profiles| profiles.get(name)) {
+        Some(profile) => profile,
+        None => {
+            return Err(ConfigError::UnknownProfile {
+                path: conf_path.to_owned(),
+                name: name.into(),
+                known: profiles.map(|p| p.keys().cloned().collect()).unwrap_or_default(),
+            });
+        }
+    };
+
+    let table = profile
+        .as_table()
+        .ok_or_else(|| schema_error(format!("profiles.{} must be a table", name)))?;
+    if let Some(key) = table.keys().find(|key| !PROFILE_SECTIONS.contains(&key.as_str())) {
+        return Err(schema_error(format!(
+            "profiles.{}.{} can't be set in a profile, only {}",
+            name,
+            key,
+            PROFILE_SECTIONS.join(", "),
+        )));
+    }
+    Ok(table.clone())
+}
+
+/// Serialize `table` as a TOML document. Going through `toml::Value` puts
+/// plain values ahead of tables, as TOML requires.
+fn table_to_string(table: &Table) -> Result<String, toml::ser::Error> {
+    toml::to_string(&toml::Value::Table(table.clone()))
+}
+
+/// Every value in `table` by dotted key.
+fn flatten(table: &Table) -> Vec<(String, toml::Value)> {
+    fn flatten_into(table: &Table, prefix: &str, values: &mut Vec<(String, toml::Value)>) {
+        for (key, value) in table.iter() {
+            let dotted = format!("{}{}", prefix, key);
+            match value {
+                toml::Value::Table(table) => flatten_into(table, &format!("{}.", dotted), values),
+                value => values.push((dotted, value.clone())),
+            }
+        }
+    }
+
+    let mut values = Vec::new();
+    flatten_into(table, "", &mut values);
+    values
+}
+
+/// Every setting by dotted key, with its default value. `version`, `profile`
+/// and the profile definitions aren't included.
+fn setting_defaults() -> Vec<(String, toml::Value)> {
+    let mut settings = Settings::default();
+    // Options that aren't set aren't serialized, so give them a value.
+    settings.tokens.path = Some(PathBuf::new());
+    settings.oauth.client_secret = SECRET_MASK.into();
+    match toml::Value::try_from(&settings) {
+        Ok(toml::Value::Table(mut table)) => {
+            table.remove("version");
+            flatten(&table)
+        }
+        _ => unreachable!("settings always serialize to a table"),
+    }
+}
+
+/// The setting the dotted `key` sets, with a profile's `profiles.<name>.`
+/// prefix left off. `None` if it doesn't name a setting.
+fn setting_key(key: &str) -> Option<&str> {
+    let setting = match key.strip_prefix("profiles.") {
+        Some(rest) => {
+            let (_, setting) = rest.split_once('.')?;
+            let (section, _) = setting.split_once('.')?;
+            if !PROFILE_SECTIONS.contains(&section) {
+                return None;
+            }
+            setting
+        }
+        None => key,
+    };
+    let known = setting == "profile" || setting_defaults().iter().any(|(dotted, _)| dotted == setting);
+    known.then_some(setting)
+}
+
+fn is_setting(key: &str) -> bool {
+    key != "profile" && !key.starts_with("profiles.") && setting_key(key).is_some()
+}
+
+/// A setting given as a `CONF_SYNC_<SECTION>__<KEY>` environment variable,
+/// e.g. `CONF_SYNC_GDRIVE__MODE=folder`.
+struct EnvOverride {
+    /// The variable's name.
+    var: String,
+    /// The name without the prefix, e.g. `GDRIVE__MODE`.
+    name: String,
+    value: String,
+}
+
+impl EnvOverride {
+    /// The dotted key the variable sets.
+    fn key(&self) -> String {
+        self.name.to_lowercase().replace(ENV_SEPARATOR, ".")
+    }
+}
+
+/// The settings given as environment variables. Variables without a
+/// section, such as `CONF_SYNC_CONFIG`, aren't settings and are left out.
+fn env_overrides() -> Vec<EnvOverride> {
+    let prefix = format!("{}_", ENV_PREFIX);
+    std::env::vars()
+        .filter_map(|(var, value)| {
+            let name = var.strip_prefix(&prefix)?.to_owned();
+            name.contains(ENV_SEPARATOR).then_some(EnvOverride { var, name, value })
+        })
+        .collect()
+}
+
+/// `env_vars` as a source of settings.
+fn environment(env_vars: &[EnvOverride]) -> Environment {
+    let source = env_vars
+        .iter()
+        .map(|env_var| (env_var.name.clone(), env_var.value.clone()))
+        .collect();
+    Environment::default()
+        .separator(ENV_SEPARATOR)
+        .try_parsing(true)
+        .source(Some(source))
+}
+
+/// Write `settings` to `conf_path`, in the format its extension names.
+pub(super) async fn write_settings(conf_path: &Path, settings: &Settings) -> Result<(), ConfigError> {
+    let unwritable = |e| ConfigError::Unwritable {
+        path: conf_path.to_owned(),
+        source: io::Error::new(io::ErrorKind::InvalidData, e),
+    };
+    let contents = match toml::Value::try_from(settings) {
+        Ok(toml::Value::Table(document)) => SettingsFormat::from_path(conf_path)
+            .serialize(&document)
+            .map_err(unwritable)?,
+        Ok(_) => unreachable!("settings always serialize to a table"),
+        Err(e) => return Err(unwritable(e.to_string())),
+    };
+    write_document(conf_path, &contents).await
+}
+
+/// Replace the settings file at `conf_path` with `contents`.
+async fn write_document(conf_path: &Path, contents: &str) -> Result<(), ConfigError> {
+    write_file_atomic(conf_path, contents.as_bytes())
+        .await
+        .map_err(|source| ConfigError::Unwritable { path: conf_path.to_owned(), source })
+}
+
+/// Write the default settings to `conf_path`, returning what was written.
+async fn init_default_config(conf_path: &Path) -> Result<String, ConfigError> {
+    write_settings(conf_path, &Settings::default()).await?;
+    tokio::fs::read_to_string(conf_path)
+        .await
+        .map_err(|source| ConfigError::Unreadable { path: conf_path.to_owned(), source })
+}
+
+fn base_dirs() -> Result<BaseDirs, ConfigError> {
+    BaseDirs::new().ok_or(ConfigError::NoHomeDir)
+}
+
+async fn create_dir(dir_path: &Path) -> Result<(), ConfigError> {
+    ensure_dir(dir_path).await.map_err(|source| ConfigError::CreateDir {
+        path: dir_path.to_owned(),
+        source,
+    })
+}
+
+async fn data_path() -> Result<PathBuf, ConfigError> {
+    let dir_path = base_dirs()?.data_local_dir().join(APP_NAME);
+    create_dir(&dir_path).await?;
+    Ok(dir_path)
+}
+
+async fn settings_dir() -> Result<PathBuf, ConfigError> {
+    let dir_path = base_dirs()?.config_dir().join(APP_NAME);
+    create_dir(&dir_path).await?;
+    Ok(dir_path)
+}
+
+fn settings_file_name(format: SettingsFormat) -> String {
+    format!("settings.{}", format.extension())
+}
+
+/// The settings file in the config directory, if there is one. They are
+/// looked for as `settings.toml`, `settings.yaml`, `settings.yml` and
+/// `settings.json`, in that order.
+async fn existing_settings_path() -> Result<Option<PathBuf>, ConfigError> {
+    let dir_path = settings_dir().await?;
+    for format in SettingsFormat::ALL {
+        for extension in format.extensions() {
+            let path = dir_path.join(format!("settings.{}", extension));
+            if file_exists(&path).await {
+                return Ok(Some(path));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The settings file in the config directory, or where a new one goes.
+async fn settings_path() -> Result<PathBuf, ConfigError> {
+    match existing_settings_path().await? {
+        Some(path) => Ok(path),
+        None => Ok(settings_dir().await?.join(settings_file_name(SettingsFormat::default()))),
+    }
+}
@@ -0,0 +1,245 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use toml::value::Table;
+use toml_edit::Item;
+use crate::fs::FileLock;
+use crate::scopes;
+use super::layers::Layer;
+use super::{write_document, ConfigError, SettingsDocument};
+
+/// The layout of the settings this version of conf-sync reads and writes.
+/// Files without a `version` predate it, and are version 0.
+pub const SETTINGS_VERSION: u32 = 1;
+
+/// `MIGRATIONS[n]` upgrades settings from version `n` to version `n + 1`.
+const MIGRATIONS: [fn(&mut SettingsDocument); SETTINGS_VERSION as usize] = [
+    extra_scopes_only,
+];
+
+/// Bring `layer` up to the current layout, in memory. Returns the version it
+/// had, if it had to be upgraded.
+pub(super) fn upgrade(layer: &mut Layer) -> Result<Option<u32>, ConfigError> {
+    let version = version(&layer.path, &layer.document)?;
+    if version == SETTINGS_VERSION {
+        return Ok(None);
+    }
+
+    let mut document = SettingsDocument::parse(&layer.path, &layer.contents)?;
+    for migration in MIGRATIONS[version as usize..].iter() {
+        migration(&mut document);
+    }
+    document
+        .set(&["version"], i64::from(SETTINGS_VERSION).into())
+        .expect("the document root is a table");
+
+    let contents = document.contents().map_err(|e| ConfigError::Unwritable {
+        path: layer.path.clone(),
+        source: io::Error::new(io::ErrorKind::InvalidData, e),
+    })?;
+    *layer = Layer::parse(&layer.path, &contents)?;
+    Ok(Some(version))
+}
+
+/// Upgrade the user's settings file in `layer` and write it back. The file
+/// as it was is kept next to it first, as `<name>.v<version>.bak`.
+pub(super) async fn upgrade_file(layer: &mut Layer) -> Result<(), ConfigError> {
+    if version(&layer.path, &layer.document)? == SETTINGS_VERSION {
+        return Ok(());
+    }
+
+    // Another conf-sync process may be upgrading or changing the file too.
+    let _lock = FileLock::exclusive(&layer.path)
+        .await
+        .map_err(|source| ConfigError::Unwritable { path: layer.path.clone(), source })?;
+    if let Some(current) = Layer::read(&layer.path).await? {
+        *layer = current;
+    }
+    let previous = layer.contents.clone();
+    let version = match upgrade(layer)? {
+        Some(version) => version,
+        None => return Ok(()),
+    };
+
+    let backup_path = backup_path(&layer.path, version);
+    write_document(&backup_path, &previous).await?;
+    write_document(&layer.path, &layer.contents).await?;
+    eprintln!(
+        "Upgraded {} to settings version {}; the previous file is kept as {}",
+        layer.path.display(),
+        SETTINGS_VERSION,
+        backup_path.display(),
+    );
+    Ok(())
+}
+
+/// The version of the settings in `document`, read from `path`.
+fn version(path: &Path, document: &Table) -> Result<u32, ConfigError> {
+    let version = match document.get("version") {
+        Some(toml::Value::Integer(version)) => *version,
+        Some(_) => {
+            return Err(ConfigError::Schema {
+                path: path.to_owned(),
+                message: "version must be a whole number".into(),
+            });
+        }
+        None => return Ok(0),
+    };
+    match u32::try_from(version) {
+        Ok(version) if version <= SETTINGS_VERSION => Ok(version),
+        _ => Err(ConfigError::UnsupportedVersion { path: path.to_owned(), version }),
+    }
+}
+
+fn backup_path(path: &Path, version: u32) -> PathBuf {
+    let mut backup_name = path.as_os_str().to_owned();
+    backup_name.push(format!(".v{}.bak", version));
+    PathBuf::from(backup_name)
+}
+
+/// Version 1: `oauth.scopes` only lists scopes on top of the ones features
+/// request, where it used to list every scope to request. The old default
+/// scopes go, so only the scopes the user added are left.
+fn extra_scopes_only(document: &mut SettingsDocument) {
+    let profiles: Vec<String> = match document.get_mut(&["profiles"]) {
+        Some(profiles) => match profiles.as_table_like() {
+            Some(profiles) => profiles.iter().map(|(name, _)| name.to_owned()).collect(),
+            None => Vec::new(),
+        },
+        None => Vec::new(),
+    };
+    let mut sections = vec![vec!["oauth", "scopes"]];
+    sections.extend(profiles.iter().map(|name| vec!["profiles", name.as_str(), "oauth", "scopes"]));
+
+    for keys in sections.iter() {
+        let scopes = match document.get_mut(keys).and_then(Item::as_array_mut) {
+            Some(scopes) => scopes,
+            None => continue,
+        };
+        let default_scopes: Vec<usize> = scopes
+            .iter()
+            .enumerate()
+            .filter(|(_, scope)| scope.as_str().is_some_and(scopes::was_default_scope))
+            .map(|(index, _)| index)
+            .collect();
+        // The space after the `[` and before the `]` belongs to the first and
+        // last scope, so keep it when they go.
+        let opening = scopes.iter().next().and_then(|scope| scope.decor().prefix()).map(String::from);
+        let closing = scopes.iter().last().and_then(|scope| scope.decor().suffix()).map(String::from);
+        for index in default_scopes.into_iter().rev() {
+            scopes.remove(index);
+        }
+        if let (Some(first), Some(opening)) = (scopes.get_mut(0), opening) {
+            first.decor_mut().set_prefix(opening);
+        }
+        let last = scopes.len().checked_sub(1).and_then(|last| scopes.get_mut(last));
+        if let (Some(last), Some(closing)) = (last, closing) {
+            last.decor_mut().set_suffix(closing);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V0: &str = r#"# Synced with conf-sync.
+[oauth]
+client_id = "id"
+scopes = [
+    "https://www.googleapis.com/auth/drive.appdata", # drive
+    "https://www.googleapis.com/auth/drive.install",
+    "https://example.com/a",
+    "email",
+    "https://example.com/b",
+    "openid",
+]
+
+[profiles.work.oauth]
+scopes = ["https://www.googleapis.com/auth/drive.file", "https://example.com/c", "profile", "email"]
+"#;
+
+    const V1: &str = r#"version = 1
+# Synced with conf-sync.
+[oauth]
+client_id = "id"
+scopes = [
+    "https://example.com/a",
+    "https://example.com/b",
+]
+
+[profiles.work.oauth]
+scopes = ["https://example.com/c"]
+"#;
+
+    /// A directory of its own for a test to write settings files in.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("conf-sync-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn upgrade_drops_the_old_default_scopes_keeping_the_layout() {
+        let mut layer = Layer::parse(Path::new("/settings.toml"), V0).unwrap();
+
+        assert_eq!(upgrade(&mut layer).unwrap(), Some(0));
+
+        assert_eq!(layer.contents, V1);
+        assert_eq!(layer.document["version"].as_integer(), Some(1));
+    }
+
+    #[test]
+    fn upgrade_leaves_current_settings_alone() {
+        let mut layer = Layer::parse(Path::new("/settings.toml"), V1).unwrap();
+
+        assert_eq!(upgrade(&mut layer).unwrap(), None);
+
+        assert_eq!(layer.contents, V1);
+    }
+
+    #[test]
+    fn upgrade_refuses_newer_settings() {
+        let contents = format!("version = {}\n", SETTINGS_VERSION + 1);
+        let mut layer = Layer::parse(Path::new("/settings.toml"), &contents).unwrap();
+
+        match upgrade(&mut layer) {
+            Err(ConfigError::UnsupportedVersion { path, version }) => {
+                assert_eq!(path, Path::new("/settings.toml"));
+                assert_eq!(version, i64::from(SETTINGS_VERSION) + 1);
+            }
+            result => panic!("expected UnsupportedVersion, got {:?}", result),
+        }
+        assert_eq!(layer.contents, contents);
+    }
+
+    #[tokio::test]
+    async fn upgrade_file_keeps_a_backup_of_the_old_file() {
+        let dir = test_dir("upgrade-file");
+        let path = dir.join("settings.toml");
+        std::fs::write(&path, V0).unwrap();
+        let mut layer = Layer::read(&path).await.unwrap().unwrap();
+
+        upgrade_file(&mut layer).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), V1);
+        assert_eq!(std::fs::read_to_string(dir.join("settings.toml.v0.bak")).unwrap(), V0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn upgrade_file_writes_nothing_for_newer_settings() {
+        let dir = test_dir("upgrade-newer");
+        let path = dir.join("settings.toml");
+        let contents = format!("version = {}\n", SETTINGS_VERSION + 1);
+        std::fs::write(&path, &contents).unwrap();
+        let mut layer = Layer::read(&path).await.unwrap().unwrap();
+
+        assert!(upgrade_file(&mut layer).await.is_err());
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), contents);
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+use toml::value::Table;
+use super::SettingsDocument;
+
+/// Name of the copy of the settings shared with other machines, kept with the
+/// synced files.
+pub const SHARED_SETTINGS_FILE: &str = "conf-sync-settings.toml";
+
+/// Leave out what stays on the machine it is set on: the client secret, where
+/// tokens are kept and which profile is selected.
+pub(super) fn sanitize(document: &mut Table) {
+    document.remove("profile");
+    document.remove("tokens");
+    remove_client_secret(document);
+    if let Some(toml::Value::Table(profiles)) = document.get_mut("profiles") {
+        for (_, profile) in profiles.iter_mut() {
+            if let toml::Value::Table(profile) = profile {
+                remove_client_secret(profile);
+            }
+        }
+    }
+}
+
+fn remove_client_secret(document: &mut Table) {
+    if let Some(toml::Value::Table(oauth)) = document.get_mut("oauth") {
+        oauth.remove("client_secret");
+    }
+}
+
+/// Write every value in `shared` into `document`, replacing the values there.
+/// Lists, like the synced files, are replaced as a whole. Nothing is removed:
+/// `shared` can't tell a setting removed on the other machine from one only
+/// set here, so a profile deleted there stays here. Returns the dotted
+/// keys that changed, or fails with the dotted key of a value in the way of a
+/// table.
+pub(super) fn merge(document: &mut SettingsDocument, shared: &Table) -> Result<Vec<String>, String> {
+    let local = document.to_table()?;
+    let mut values = Vec::new();
+    collect_values(shared, &mut Vec::new(), &mut values);
+
+    let mut changed = Vec::new();
+    for (keys, value) in values.iter() {
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        if lookup(&local, &keys) == Some(value) {
+            continue;
+        }
+        document.set_toml(&keys, value)?;
+        changed.push(keys.join("."));
+    }
+    Ok(changed)
+}
+
+/// Every value in `table` that isn't a table, by the keys leading to it.
+fn collect_values(table: &Table, keys: &mut Vec<String>, values: &mut Vec<(Vec<String>, toml::Value)>) {
+    for (key, value) in table.iter() {
+        keys.push(key.clone());
+        match value {
+            toml::Value::Table(table) => collect_values(table, keys, values),
+            value => values.push((keys.clone(), value.clone())),
+        }
+        keys.pop();
+    }
+}
+
+fn lookup<'a>(table: &'a Table, keys: &[&str]) -> Option<&'a toml::Value> {
+    let (key, parents) = keys.split_last()?;
+    let mut table = table;
+    for parent in parents {
+        table = table.get(*parent)?.as_table()?;
+    }
+    table.get(*key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    fn table(contents: &str) -> Table {
+        toml::from_str(contents).unwrap()
+    }
+
+    fn document(contents: &str) -> SettingsDocument {
+        SettingsDocument::parse(Path::new("settings.toml"), contents).unwrap()
+    }
+
+    #[test]
+    fn sanitize_keeps_secrets_and_tokens_on_this_machine() {
+        let mut settings = table(
+            "version = 1\n\
+             profile = \"work\"\n\
+             [gdrive]\nmode = \"folder\"\n\
+             [oauth]\nclient_id = \"id\"\nclient_secret = \"secret\"\n\
+             [tokens]\nstorage = \"file\"\npath = \"/home/user/tokens.json\"\n\
+             [profiles.work.oauth]\nclient_id = \"work-id\"\nclient_secret = \"work-secret\"\n",
+        );
+
+        sanitize(&mut settings);
+
+        assert_eq!(
+            settings,
+            table(
+                "version = 1\n\
+                 [gdrive]\nmode = \"folder\"\n\
+                 [oauth]\nclient_id = \"id\"\n\
+                 [profiles.work.oauth]\nclient_id = \"work-id\"\n",
+            ),
+        );
+        let shared = toml::to_string(&toml::Value::Table(settings.clone())).unwrap();
+        assert!(!shared.contains("secret"), "{}", shared);
+        assert!(!shared.contains("tokens"), "{}", shared);
+    }
+
+    #[test]
+    fn merge_keeps_local_comments() {
+        let mut local = document(
+            "# My settings.\n\
+             [gdrive]\n\
+             # Where files go.\n\
+             mode = \"appdata\" # for now\n\
+             app_folder = \"conf-sync\"\n",
+        );
+        let shared = table("[gdrive]\nmode = \"folder\"\napp_folder = \"conf-sync\"\n[sync]\nfiles = [\"a\"]\n");
+
+        let changed = merge(&mut local, &shared).unwrap();
+
+        assert_eq!(changed, ["gdrive.mode", "sync.files"]);
+        assert_eq!(
+            local.contents().unwrap(),
+            "# My settings.\n\
+             [gdrive]\n\
+             # Where files go.\n\
+             mode = \"folder\" # for now\n\
+             app_folder = \"conf-sync\"\n\
+             \n\
+             [sync]\n\
+             files = [\"a\"]\n",
+        );
+    }
+
+    #[test]
+    fn merge_keeps_settings_the_other_machine_does_not_have() {
+        let mut local = document(
+            "[profiles.home.gdrive]\napp_folder = \"home\"\n\
+             [profiles.work.gdrive]\napp_folder = \"work\"\n",
+        );
+        let shared = table("[profiles.work.gdrive]\napp_folder = \"work\"\n");
+
+        let changed = merge(&mut local, &shared).unwrap();
+
+        assert!(changed.is_empty());
+        let merged = local.to_table().unwrap();
+        assert_eq!(merged["profiles"]["home"]["gdrive"]["app_folder"].as_str(), Some("home"));
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt;
+use url::Url;
+use super::Settings;
+
+/// Longest folder name Google Drive shows without truncating it.
+const MAX_FOLDER_NAME_LEN: usize = 255;
+
+/// Something wrong with one setting.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    /// The setting, as `section.key`.
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+/// Check the settings for values conf-sync can't work with. Every problem is
+/// collected, so they can all be fixed in one go.
+pub fn validate(settings: &Settings) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut problem = |key: &str, message: String| {
+        problems.push(Problem { key: key.into(), message });
+    };
+
+    if let Err(message) = check_folder_name(&settings.gdrive.app_folder) {
+        problem("gdrive.app_folder", message);
+    }
+
+    let oauth = &settings.oauth;
+    if oauth.client_id.trim().is_empty() {
+        problem(
+            "oauth.client_id",
+            "must be set; import a client with `conf-sync auth import-client`".into(),
+        );
+    }
+    for (key, value) in [("oauth.auth_uri", &oauth.auth_uri), ("oauth.token_uri", &oauth.token_uri)] {
+        if let Err(message) = check_url(value) {
+            problem(key, message);
+        }
+    }
+    if oauth.redirect_uris.is_empty() {
+        problem("oauth.redirect_uris", "must list at least one URI".into());
+    }
+    // Installed apps may use the out-of-band `urn:` URI, so any scheme goes.
+    for uri in oauth.redirect_uris.iter() {
+        if let Err(e) = Url::parse(uri) {
+            problem("oauth.redirect_uris", format!("'{}' is not a valid URI: {}", uri, e));
+        }
+    }
+    if oauth.scopes.iter().any(|scope| scope.trim().is_empty()) {
+        problem("oauth.scopes", "must not contain empty scopes".into());
+    }
+    if oauth.auth_timeout == 0 {
+        problem("oauth.auth_timeout", "must be at least 1 second".into());
+    }
+
+    // Profile names become directory names for the profile's tokens.
+    for name in settings.profiles.keys() {
+        if let Err(message) = check_folder_name(name) {
+            problem(&format!("profiles.{}", name), message);
+        }
+    }
+
+    if let Some(path) = &settings.tokens.path {
+        if path.as_os_str().is_empty() {
+            problem("tokens.path", "must not be empty; leave it out to use the default".into());
+        }
+    }
+
+    problems
+}
+
+fn check_url(value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err("must be set".into());
+    }
+    match Url::parse(value) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Ok(()),
+        Ok(url) => Err(format!("'{}' must be an http or https URL, not {}", value, url.scheme())),
+        Err(e) => Err(format!("'{}' is not a valid URL: {}", value, e)),
+    }
+}
+
+/// Synced files are kept in a folder of this name, and profiles' tokens in a
+/// directory of theirs, so either has to be a single path component.
+pub(super) fn check_folder_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("must not be empty".into());
+    }
+    if name == "." || name == ".." {
+        return Err(format!("'{}' is not a usable folder name", name));
+    }
+    if name != name.trim() {
+        return Err(format!("'{}' must not start or end with whitespace", name));
+    }
+    if let Some(c) = name.chars().find(|c| matches!(c, '/' | '\\') || c.is_control()) {
+        return Err(format!("'{}' must not contain {:?}", name, c));
+    }
+    if name.len() > MAX_FOLDER_NAME_LEN {
+        return Err(format!("must be at most {} bytes long", MAX_FOLDER_NAME_LEN));
+    }
+    Ok(())
+}
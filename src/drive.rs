@@ -0,0 +1,134 @@
+use std::io::Cursor;
+use drive3::{api, hyper, DriveHub, Error};
+use crate::scopes::DriveMode;
+
+/// The id Drive knows the hidden application data folder by.
+const APP_DATA_FOLDER: &str = "appDataFolder";
+const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+
+/// The space synced files are kept in, for listing files.
+fn space(mode: DriveMode) -> &'static str {
+    match mode {
+        DriveMode::AppData => APP_DATA_FOLDER,
+        DriveMode::Folder => "drive",
+    }
+}
+
+/// `value` as a string literal in a Drive search query.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// The id of the folder synced files are kept in: the application data
+/// folder, or the folder called `app_folder` at the top of the user's Drive.
+/// That folder is created if `create` is set, otherwise `None` means there is
+/// none yet.
+pub async fn app_folder(
+    hub: &DriveHub,
+    mode: DriveMode,
+    app_folder: &str,
+    scopes: &[String],
+    create: bool,
+) -> Result<Option<String>, Error> {
+    if mode == DriveMode::AppData {
+        return Ok(Some(APP_DATA_FOLDER.into()));
+    }
+
+    let mut req = hub.files().list().spaces(space(mode)).q(&format!(
+        "name = {} and mimeType = {} and 'root' in parents and trashed = false",
+        quote(app_folder),
+        quote(FOLDER_MIME_TYPE),
+    ));
+    for scope in scopes.iter() {
+        req = req.add_scope(scope);
+    }
+    let (_, list) = req.doit().await?;
+    if let Some(id) = list.files.unwrap_or_default().into_iter().find_map(|file| file.id) {
+        return Ok(Some(id));
+    }
+    if !create {
+        return Ok(None);
+    }
+
+    let folder = api::File {
+        name: Some(app_folder.into()),
+        mime_type: Some(FOLDER_MIME_TYPE.into()),
+        ..api::File::default()
+    };
+    let mut create = hub.files().create(folder);
+    for scope in scopes.iter() {
+        create = create.add_scope(scope);
+    }
+    let (_, folder) = create
+        .upload(Cursor::new(Vec::new()), FOLDER_MIME_TYPE.parse().unwrap())
+        .await?;
+    Ok(folder.id)
+}
+
+/// The id of the file called `name` in the folder `folder`, if there is one.
+pub async fn find_file(
+    hub: &DriveHub,
+    mode: DriveMode,
+    scopes: &[String],
+    folder: &str,
+    name: &str,
+) -> Result<Option<String>, Error> {
+    let mut req = hub.files().list().spaces(space(mode)).q(&format!(
+        "name = {} and {} in parents and trashed = false",
+        quote(name),
+        quote(folder),
+    ));
+    for scope in scopes.iter() {
+        req = req.add_scope(scope);
+    }
+    let (_, list) = req.doit().await?;
+    Ok(list.files.unwrap_or_default().into_iter().find_map(|file| file.id))
+}
+
+/// Store `contents` as the file called `name` in the folder `folder`,
+/// replacing the file's contents if it already exists.
+pub async fn put_file(
+    hub: &DriveHub,
+    mode: DriveMode,
+    scopes: &[String],
+    folder: &str,
+    name: &str,
+    contents: Vec<u8>,
+) -> Result<(), Error> {
+    let mime_type = "application/octet-stream".parse().unwrap();
+    match find_file(hub, mode, scopes, folder, name).await? {
+        Some(id) => {
+            let mut update = hub.files().update(api::File::default(), &id);
+            for scope in scopes.iter() {
+                update = update.add_scope(scope);
+            }
+            update.upload(Cursor::new(contents), mime_type).await?;
+        }
+        None => {
+            let file = api::File {
+                name: Some(name.into()),
+                parents: Some(vec![folder.into()]),
+                ..api::File::default()
+            };
+            let mut create = hub.files().create(file);
+            for scope in scopes.iter() {
+                create = create.add_scope(scope);
+            }
+            create.upload(Cursor::new(contents), mime_type).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Download the contents of the file `id`.
+pub async fn get_file(hub: &DriveHub, scopes: &[String], id: &str) -> Result<Vec<u8>, Error> {
+    let mut req = hub.files().get(id).param("alt", "media");
+    for scope in scopes.iter() {
+        req = req.add_scope(scope);
+    }
+    let (response, _) = req.doit().await?;
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(Error::HttpError)?;
+    Ok(body.to_vec())
+}
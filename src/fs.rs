@@ -21,7 +21,7 @@ pub async fn open_writeable_file(
     use std::os::unix::fs::OpenOptionsExt;
     let opts: tokio::fs::OpenOptions = {
         let mut opts = std::fs::OpenOptions::new();
-        opts.write(true).create(true).mode(0o600);
+        opts.write(true).create(true).truncate(true).mode(0o600);
         opts.into()
     };
     let dirname = match Path::new(filename.as_ref()).parent() {
@@ -54,4 +54,96 @@ pub async fn open_writeable_file(
     // I don't have knowledge of windows or other platforms to know how to
     // create a file that's only readable by the current user.
     tokio::fs::File::create(filename).await
-}
\ No newline at end of file
+}
+
+/// Replace the contents of `filename` without ever leaving a partially written
+/// file behind. The data is written to a temporary file next to it, synced and
+/// then renamed over the original. Each write gets a temporary file of its
+/// own, so writers in other processes don't rename each other's.
+pub async fn write_file_atomic(
+    filename: impl AsRef<Path>,
+    contents: &[u8],
+) -> Result<(), tokio::io::Error> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::AsyncWriteExt;
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+    let filename = filename.as_ref();
+    let mut tmp_name = filename.as_os_str().to_owned();
+    tmp_name.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed),
+    ));
+    let tmp_path = Path::new(&tmp_name);
+
+    let mut f = open_writeable_file(tmp_path).await?;
+    f.write_all(contents).await?;
+    f.sync_all().await?;
+    drop(f);
+
+    tokio::fs::rename(tmp_path, filename).await
+}
+
+/// Overwrite `filename` with zeros before deleting it, so the old contents
+/// aren't trivially recoverable from the disk blocks it used.
+pub async fn secure_remove_file(filename: impl AsRef<Path>) -> Result<(), tokio::io::Error> {
+    use tokio::io::AsyncWriteExt;
+    let filename = filename.as_ref();
+    let len = tokio::fs::metadata(filename).await?.len();
+
+    let mut f = tokio::fs::OpenOptions::new().write(true).open(filename).await?;
+    f.write_all(&vec![0u8; len as usize]).await?;
+    f.sync_all().await?;
+    drop(f);
+
+    tokio::fs::remove_file(filename).await
+}
+
+/// An advisory lock shared by every conf-sync process using `filename`. It is
+/// taken on a `<name>.lock` file next to it, because files written with
+/// `write_file_atomic` are replaced rather than modified in place. The lock is
+/// released when dropped.
+pub struct FileLock {
+    file: fs::File,
+}
+
+impl FileLock {
+    /// Wait for a lock that still lets other processes read `filename`.
+    pub async fn shared(filename: impl AsRef<Path>) -> io::Result<FileLock> {
+        FileLock::acquire(filename.as_ref(), false).await
+    }
+
+    /// Wait for a lock that keeps every other process away from `filename`,
+    /// held while it is read, modified and written back.
+    pub async fn exclusive(filename: impl AsRef<Path>) -> io::Result<FileLock> {
+        FileLock::acquire(filename.as_ref(), true).await
+    }
+
+    async fn acquire(filename: &Path, exclusive: bool) -> io::Result<FileLock> {
+        use fs2::FileExt;
+        let mut lock_name = filename.as_os_str().to_owned();
+        lock_name.push(".lock");
+
+        let file = open_writeable_file(&lock_name).await?.into_std().await;
+        // Taking the lock blocks until the other process lets go of it, so
+        // keep it off the async workers.
+        tokio::task::spawn_blocking(move || {
+            if exclusive {
+                FileExt::lock_exclusive(&file)?;
+            } else {
+                FileExt::lock_shared(&file)?;
+            }
+            Ok(FileLock { file })
+        })
+        .await
+        .map_err(Error::other)?
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        use fs2::FileExt;
+        let _ = FileExt::unlock(&self.file);
+    }
+}
@@ -1,9 +1,6 @@
 // extern crate hyper;
 // extern crate hyper_rustls;
 extern crate google_drive3 as drive3;
-use std::path::Path;
-use std::fs::File;
-use drive3::{api};
 use drive3::{Error};
 use drive3::{DriveHub, hyper, hyper_rustls};
 use yup_oauth2::{
@@ -11,26 +8,82 @@ use yup_oauth2::{
     InstalledFlowReturnMethod,
 };
 
+use clap::Parser;
+
 pub mod fs;
-// pub mod config;
 pub mod config_handler;
 mod auth;
+mod cli;
+mod commands;
+mod drive;
+mod scopes;
 mod token_storage;
 
-use config_handler::ConfigHandler;
-use token_storage::KeychainStorage;
+use auth::{AuthFlowError, FlowOptions};
+use cli::{Cli, Command};
+use config_handler::{ConfigHandler, SHARED_SETTINGS_FILE};
+use scopes::{DriveMode, Feature};
 
 #[tokio::main]
 async fn main() {
-    let cfg = ConfigHandler::new().await;
-    let oauth_config = cfg.oauth_config.clone();
-    let app_secret = cfg.get_app_secret();
-    let fs_keystorage_path = cfg.base_paths.data_dir.join("keystorage.json");
+    let cli = Cli::parse();
+    // Loading the settings would write the defaults if there are none yet.
+    if let Some(Command::Init { format }) = cli.command {
+        std::process::exit(commands::init::run(cli.config, format).await);
+    }
+
+    let mut cfg = match ConfigHandler::new(cli.config, cli.profile).await {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(commands::EXIT_CONFIG);
+        }
+    };
+
+    for warning in cfg.warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+
+    if cli.command.as_ref().is_none_or(Command::requires_valid_settings) {
+        if let Err(e) = cfg.validate() {
+            eprintln!("{}", e);
+            std::process::exit(commands::EXIT_CONFIG);
+        }
+    }
+
+    match cli.command {
+        Some(Command::Auth(command)) => {
+            std::process::exit(commands::auth::run(&mut cfg, command).await);
+        }
+        Some(Command::Config(command)) => {
+            let interactive = !cli.non_interactive;
+            std::process::exit(commands::config::run(&mut cfg, command, interactive).await);
+        }
+        Some(Command::Profile(command)) => {
+            std::process::exit(commands::profile::run(&mut cfg, command).await);
+        }
+        Some(Command::Pull) => std::process::exit(pull(cfg, !cli.non_interactive).await),
+        Some(Command::Init { .. }) => unreachable!("init runs before the settings are loaded"),
+        None => std::process::exit(sync(cfg, !cli.non_interactive).await),
+    }
+}
+
+/// Sign in and connect to Google Drive. Fails with the exit code to use.
+async fn connect(cfg: &ConfigHandler, interactive: bool) -> Result<DriveHub, i32> {
+    let drive_scopes = cfg.feature_scopes(cfg.drive_feature());
+    let app_secret = match cfg.get_app_secret() {
+        Ok(app_secret) => app_secret,
+        Err(err) => {
+            eprintln!("{}", err);
+            return Err(commands::EXIT_CONFIG);
+        }
+    };
 
-    let key_storage = match KeychainStorage::new(None).await {
+    let key_storage = match cfg.open_token_store().await {
         Ok(storage) => storage,
         Err(err) => {
-            panic!("Unable to initialize keychain storage: {}", err);
+            eprintln!("Unable to open the token storage: {}", err);
+            return Err(commands::EXIT_FAILURE);
         }
     };
 
@@ -38,20 +91,69 @@ async fn main() {
         app_secret,
         InstalledFlowReturnMethod::HTTPRedirect,
     )
-        .with_storage(Box::new(key_storage))
+        .with_storage(Box::new(key_storage.clone()))
         .flow_delegate(Box::new(auth::WebLauncherInstallFlowDelegate))
-        .build().await.unwrap();
+        .build().await;
+    let auth_handler = match auth_handler {
+        Ok(auth_handler) => auth_handler,
+        Err(err) => {
+            eprintln!("Unable to set up authorization: {}", err);
+            return Err(commands::EXIT_FAILURE);
+        }
+    };
+
+    // Sign in up front, where the flow can time out or be cancelled, rather
+    // than letting the first Drive request wait on the browser indefinitely.
+    let flow_options = FlowOptions {
+        timeout: cfg.auth_timeout(),
+        interactive,
+    };
+    if let Err(err) = auth::authorize(&auth_handler, &key_storage, &drive_scopes, flow_options).await {
+        eprintln!("{}", err);
+        return Err(match err {
+            AuthFlowError::Cancelled => commands::EXIT_CANCELLED,
+            AuthFlowError::ReauthRequired => commands::EXIT_REAUTH_REQUIRED,
+            _ => commands::EXIT_FAILURE,
+        });
+    }
 
-    let hub = DriveHub::new(
+    if cfg.oauth_config.show_account {
+        let account_scopes = cfg.feature_scopes(Feature::AccountDisplay);
+        let account = match auth::authorize(&auth_handler, &key_storage, &account_scopes, flow_options).await {
+            Ok(()) => auth_handler.id_token(&account_scopes).await.map_err(|e| e.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        match account {
+            Ok(id_token) => {
+                if let Some(email) = id_token.as_deref().and_then(auth::id_token_email) {
+                    println!("Signed in as {}", email);
+                }
+            }
+            Err(err) => println!("Unable to determine signed in account: {}", err),
+        }
+    }
+
+    Ok(DriveHub::new(
         hyper::Client::builder().build(
             hyper_rustls::HttpsConnector::with_native_roots()
         ),
-        auth_handler
-    );
+        auth_handler.clone()
+    ))
+}
+
+async fn sync(cfg: ConfigHandler, interactive: bool) -> i32 {
+    let drive_scopes = cfg.feature_scopes(cfg.drive_feature());
+    let hub = match connect(&cfg, interactive).await {
+        Ok(hub) => hub,
+        Err(exit_code) => return exit_code,
+    };
 
     let mut req = hub.files().list();
-    
-    for scope in oauth_config.scopes.iter() {
+    if cfg.settings.gdrive.mode == DriveMode::AppData {
+        req = req.spaces("appDataFolder");
+    }
+
+    for scope in drive_scopes.iter() {
         req = req.add_scope(scope);
     }
     
@@ -74,41 +176,144 @@ async fn main() {
         Ok(res) => println!("Success: {:?}", res)
     }
 
-    let upload_file = match File::open(
-        Path::new("/home/jeremy/.local/share/conf-sync/keystorage.json")
-    ) {
-        Ok(f) => f,
-        Err(err) => {
-            panic!("Could not open file: {}", err);
+    let mut exit_code = commands::EXIT_OK;
+    if cfg.settings.sync.files.is_empty() && !cfg.settings.sync.settings {
+        return exit_code;
+    }
+
+    let gdrive = &cfg.settings.gdrive;
+    let folder = match drive::app_folder(&hub, gdrive.mode, &gdrive.app_folder, &drive_scopes, true).await {
+        Ok(Some(folder)) => folder,
+        Ok(None) => {
+            eprintln!("Unable to create the folder {}", gdrive.app_folder);
+            return commands::EXIT_FAILURE;
+        }
+        Err(e) => {
+            eprintln!("Unable to find the folder {}: {}", gdrive.app_folder, e);
+            return commands::EXIT_FAILURE;
         }
     };
 
-    let file_req = api::File {
-        name: Some("keystorage.json".into()),
-        ..api::File::default()
+    for path in cfg.settings.sync.files.iter() {
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                eprintln!("Could not open {}: {}", path.display(), err);
+                exit_code = commands::EXIT_FAILURE;
+                continue;
+            }
+        };
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => {
+                eprintln!("{} is not a file", path.display());
+                exit_code = commands::EXIT_FAILURE;
+                continue;
+            }
+        };
+
+        match drive::put_file(&hub, gdrive.mode, &drive_scopes, &folder, &name, contents).await {
+            Ok(()) => println!("Uploaded {}", path.display()),
+            Err(e) => {
+                eprintln!("Unable to upload {}: {}", path.display(), e);
+                exit_code = commands::EXIT_FAILURE;
+            }
+        }
+    }
+
+    if cfg.settings.sync.settings {
+        let pushed = match cfg.shared_settings() {
+            Ok(contents) => {
+                drive::put_file(&hub, gdrive.mode, &drive_scopes, &folder, SHARED_SETTINGS_FILE, contents.into_bytes())
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        match pushed {
+            Ok(()) => println!("Shared the settings as {}", SHARED_SETTINGS_FILE),
+            Err(err) => {
+                eprintln!("Unable to share the settings: {}", err);
+                exit_code = commands::EXIT_FAILURE;
+            }
+        }
+    }
+
+    exit_code
+}
+
+/// Merge the settings shared by another machine into the user's settings
+/// file. The merge is undone if it introduces a problem. Settings removed on
+/// the other machine are kept here.
+async fn pull(mut cfg: ConfigHandler, interactive: bool) -> i32 {
+    let drive_scopes = cfg.feature_scopes(cfg.drive_feature());
+    let hub = match connect(&cfg, interactive).await {
+        Ok(hub) => hub,
+        Err(exit_code) => return exit_code,
     };
 
-    let push = hub.files().create(file_req).upload(
-        upload_file,
-        "application/json".parse().unwrap(),
-    ).await;
+    let gdrive = &cfg.settings.gdrive;
+    let shared_file = match drive::app_folder(&hub, gdrive.mode, &gdrive.app_folder, &drive_scopes, false).await {
+        Ok(Some(folder)) => {
+            drive::find_file(&hub, gdrive.mode, &drive_scopes, &folder, SHARED_SETTINGS_FILE).await
+        }
+        Ok(None) => Ok(None),
+        Err(e) => Err(e),
+    };
+    let contents = match shared_file {
+        Ok(Some(id)) => drive::get_file(&hub, &drive_scopes, &id).await,
+        Ok(None) => {
+            println!("No settings have been shared; set settings = true under [sync] on the machine to share them from.");
+            return commands::EXIT_OK;
+        }
+        Err(e) => Err(e),
+    };
+    let contents = match contents.map(String::from_utf8) {
+        Ok(Ok(contents)) => contents,
+        Ok(Err(e)) => {
+            eprintln!("Unable to read the shared settings: {}", e);
+            return commands::EXIT_FAILURE;
+        }
+        Err(e) => {
+            eprintln!("Unable to download the shared settings: {}", e);
+            return commands::EXIT_FAILURE;
+        }
+    };
 
-    match push {
-        Err(e) => match e {
-            // The Error enum provides details about what exactly happened.
-            // You can also just use its `Debug`, `Display` or `Error` traits
-            Error::HttpError(_)
-            |Error::Io(_)
-            |Error::MissingAPIKey
-            |Error::MissingToken(_)
-            |Error::Cancelled
-            |Error::UploadSizeLimitExceeded(_, _)
-            |Error::Failure(_)
-            |Error::BadRequest(_)
-            |Error::FieldClash(_)
-            |Error::JsonDecodeError(_, _) => println!("{}", e),
-        },
-        Ok(res) => println!("Success: {:?}", res)
+    let _lock = match cfg.lock_settings().await {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("{}", e);
+            return commands::EXIT_FAILURE;
+        }
+    };
+    let previous = cfg.clone();
+    let changed = match cfg.merge_shared(&contents) {
+        Ok(changed) => changed,
+        Err(e) => {
+            eprintln!("{}", e);
+            return commands::EXIT_CONFIG;
+        }
+    };
+    if changed.is_empty() {
+        println!("The settings are up to date.");
+        return commands::EXIT_OK;
+    }
+    if let Err(e) = cfg.save().await {
+        eprintln!("{}", e);
+        return commands::EXIT_FAILURE;
     }
 
+    let conf_path = &cfg.base_paths.conf_path;
+    if let Err(e) = commands::config::check_changes(&previous).await {
+        eprintln!("{}", e);
+        if let Err(e) = previous.save().await {
+            eprintln!("Unable to undo the merge: {}", e);
+            return commands::EXIT_FAILURE;
+        }
+        eprintln!("{} was left unchanged.", conf_path.display());
+        return commands::EXIT_CONFIG;
+    }
+    println!("Updated {} in {}.", changed.join(", "), conf_path.display());
+    commands::EXIT_OK
 }
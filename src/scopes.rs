@@ -0,0 +1,119 @@
+use serde_derive::{ Deserialize, Serialize };
+
+pub const DRIVE_APPDATA: &str = "https://www.googleapis.com/auth/drive.appdata";
+pub const DRIVE_FILE: &str = "https://www.googleapis.com/auth/drive.file";
+pub const OPENID: &str = "openid";
+pub const EMAIL: &str = "email";
+
+const USERINFO_EMAIL: &str = "https://www.googleapis.com/auth/userinfo.email";
+const USERINFO_PROFILE: &str = "https://www.googleapis.com/auth/userinfo.profile";
+const DRIVE_INSTALL: &str = "https://www.googleapis.com/auth/drive.install";
+
+/// Scopes that used to be requested by default, before scopes were derived
+/// from the enabled features, and that no feature needs any more.
+const RETIRED_DEFAULT_SCOPES: [&str; 2] = [DRIVE_INSTALL, USERINFO_PROFILE];
+
+/// Where synced files are kept on Google Drive.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DriveMode {
+    /// The hidden application data folder, invisible to the user in Drive.
+    #[default]
+    #[serde(rename = "appdata")]
+    AppData,
+    /// A regular folder the user can browse in Drive.
+    Folder,
+}
+
+/// A piece of functionality that needs its own OAuth scopes. Scopes are only
+/// requested when the feature is first used, so a user who never turns on a
+/// feature is never asked to grant its access.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Feature {
+    AppData,
+    VisibleFolder,
+    AccountDisplay,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 3] = [Feature::AppData, Feature::VisibleFolder, Feature::AccountDisplay];
+
+    pub fn scopes(self) -> &'static [&'static str] {
+        match self {
+            Feature::AppData => &[DRIVE_APPDATA],
+            Feature::VisibleFolder => &[DRIVE_FILE],
+            Feature::AccountDisplay => &[OPENID, EMAIL],
+        }
+    }
+}
+
+impl From<DriveMode> for Feature {
+    fn from(mode: DriveMode) -> Self {
+        match mode {
+            DriveMode::AppData => Feature::AppData,
+            DriveMode::Folder => Feature::VisibleFolder,
+        }
+    }
+}
+
+/// Collect the scopes needed by `features`, plus any `extra` scopes from the
+/// settings, without duplicates.
+pub fn scopes_for<T>(features: &[Feature], extra: &[T]) -> Vec<String>
+where
+    T: AsRef<str>,
+{
+    let mut scopes: Vec<String> = Vec::new();
+    let requested = features
+        .iter()
+        .flat_map(|feature| feature.scopes().iter().copied())
+        .chain(extra.iter().map(|scope| scope.as_ref()));
+
+    for scope in requested {
+        if !scopes.iter().any(|s| canonical(s) == canonical(scope)) {
+            scopes.push(scope.into());
+        }
+    }
+    scopes
+}
+
+/// Whether one of conf-sync's features requests `scope` by itself, in any
+/// spelling.
+pub fn is_feature_scope(scope: &str) -> bool {
+    Feature::ALL
+        .iter()
+        .flat_map(|feature| feature.scopes().iter())
+        .any(|s| canonical(s) == canonical(scope))
+}
+
+/// Whether `scope` was one conf-sync requested by default in the past, so
+/// settings listing it didn't add it themselves.
+pub fn was_default_scope(scope: &str) -> bool {
+    is_feature_scope(scope)
+        || RETIRED_DEFAULT_SCOPES.iter().any(|s| canonical(s) == canonical(scope))
+}
+
+/// Google accepts some scopes under a short alias as well as their full URL.
+/// Map `scope` to the URL Google reports it as granted under, so both
+/// spellings are treated as the same scope.
+pub fn canonical(scope: &str) -> &str {
+    match scope.trim() {
+        "email" => USERINFO_EMAIL,
+        "profile" => USERINFO_PROFILE,
+        scope => scope,
+    }
+}
+
+/// Canonicalize `scopes` and drop duplicates. The result is sorted, so equal
+/// sets of scopes always come out the same.
+pub fn normalize<T>(scopes: &[T]) -> Vec<String>
+where
+    T: AsRef<str>,
+{
+    let mut normalized: Vec<String> = scopes
+        .iter()
+        .map(|scope| canonical(scope.as_ref()).to_owned())
+        .collect();
+    normalized.sort();
+    normalized.dedup();
+    normalized
+}
@@ -0,0 +1,281 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use yup_oauth2::storage::{
+    TokenStorage,
+    TokenInfo,
+};
+use super::json_tokens::{JSONTokens, JSONToken, ScopeSet};
+use super::locked_file::{LockedFile, TokenFormat};
+use crate::fs::{write_file_atomic, FileLock};
+
+/// Read the passphrase from this variable instead of prompting for it.
+pub const PASSPHRASE_ENV: &str = "CONF_SYNC_PASSPHRASE";
+
+const FORMAT_VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+
+/// On-disk layout of the encrypted token file. Binary fields are base64.
+#[derive(Serialize, Deserialize)]
+struct EncryptedFile {
+    version: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Stores tokens in a file encrypted with a key derived from a passphrase,
+/// for hosts where the keyring can't be used.
+#[derive(Clone)]
+pub struct EncryptedFileStorage {
+    file: LockedFile<Encryption>,
+    filename: PathBuf,
+}
+
+/// The key the token file is encrypted with, and the salt it was derived
+/// with.
+#[derive(Clone)]
+struct Encryption {
+    salt: [u8; SALT_LEN],
+    key: Key,
+}
+
+impl EncryptedFileStorage {
+    pub async fn new(filename: PathBuf, passphrase: &str) -> Result<Self, io::Error> {
+        let existing = {
+            let _lock = FileLock::shared(&filename).await?;
+            read_file(&filename).await?
+        };
+        let salt: [u8; SALT_LEN] = match existing {
+            Some(existing) => decode(&existing.salt)?
+                .try_into()
+                .map_err(|_| invalid_data("Malformed salt in token file"))?,
+            None => {
+                let mut salt = [0u8; SALT_LEN];
+                OsRng.fill_bytes(&mut salt);
+                salt
+            }
+        };
+        let encryption = Encryption {
+            key: derive_key(passphrase, &salt)?,
+            salt,
+        };
+
+        Ok(EncryptedFileStorage {
+            file: LockedFile::open(Some(filename.clone()), encryption).await?,
+            filename,
+        })
+    }
+
+    /// Read the token file again with the key already derived, without
+    /// asking for the passphrase a second time.
+    pub async fn reload(&self) -> Result<Self, io::Error> {
+        Ok(EncryptedFileStorage {
+            file: LockedFile::open(Some(self.filename.clone()), self.file.format().clone()).await?,
+            filename: self.filename.clone(),
+        })
+    }
+
+    pub fn filename(&self) -> &Path {
+        &self.filename
+    }
+
+    pub async fn tokens(&self) -> Vec<JSONToken> {
+        self.file.current().await.iter().cloned().collect()
+    }
+
+    pub async fn find_token<T>(&self, scopes: &[T]) -> Option<JSONToken>
+    where
+        T: AsRef<str>,
+    {
+        self.file.current().await.find(ScopeSet::from(scopes)).cloned()
+    }
+
+    pub async fn remove_token<T>(&self, scopes: &[T]) -> anyhow::Result<Option<JSONToken>>
+    where
+        T: AsRef<str>,
+    {
+        let mut removed = None;
+        self.file
+            .update(|tokens| {
+                removed = tokens.remove(ScopeSet::from(scopes));
+                Ok(removed.is_some())
+            })
+            .await?;
+        Ok(removed)
+    }
+}
+
+#[async_trait]
+impl TokenFormat for Encryption {
+    /// Decrypt the token file with the key already derived.
+    async fn read(&self, filename: &Path) -> Result<JSONTokens, io::Error> {
+        let file = match read_file(filename).await? {
+            Some(file) => file,
+            None => return Ok(JSONTokens::new()),
+        };
+        // Another process created the file with a salt of its own, so the key
+        // derived here can't open it.
+        if decode(&file.salt)? != self.salt {
+            return Err(invalid_data(
+                "The token file was re-created by another conf-sync process; run the command again",
+            ));
+        }
+        decrypt(&file, &self.key)
+    }
+
+    /// Encrypt `tokens` with a fresh nonce and replace the token file.
+    async fn write(&self, filename: &Path, tokens: &JSONTokens) -> Result<(), io::Error> {
+        let plaintext = serde_json::to_vec(tokens)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = XChaCha20Poly1305::new(&self.key)
+            .encrypt(&nonce, plaintext.as_ref())
+            .map_err(|_| invalid_data("Unable to encrypt the token file"))?;
+
+        let file = EncryptedFile {
+            version: FORMAT_VERSION,
+            salt: encode(&self.salt),
+            nonce: encode(&nonce),
+            ciphertext: encode(&ciphertext),
+        };
+        let json = serde_json::to_vec(&file)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_file_atomic(filename, &json).await
+    }
+}
+
+#[async_trait]
+impl TokenStorage for EncryptedFileStorage {
+    async fn set(
+        &self,
+        scopes: &[&str],
+        token: TokenInfo
+    ) -> anyhow::Result<()> {
+        self.file
+            .update(|tokens| tokens.set(ScopeSet::from(scopes), token).map(|()| true))
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, scopes: &[&str]) -> Option<TokenInfo> {
+        self.file.current().await.get(ScopeSet::from(scopes))
+    }
+}
+
+async fn read_file(filename: &Path) -> Result<Option<EncryptedFile>, io::Error> {
+    let file: EncryptedFile = match tokio::fs::read(filename).await {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    if file.version != FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported token file version {}", file.version),
+        ));
+    }
+    Ok(Some(file))
+}
+
+fn decrypt(file: &EncryptedFile, key: &Key) -> Result<JSONTokens, io::Error> {
+    let nonce = decode(&file.nonce)?;
+    if nonce.len() != XNonce::default().len() {
+        return Err(invalid_data("Malformed nonce in token file"));
+    }
+
+    let plaintext = XChaCha20Poly1305::new(key)
+        .decrypt(XNonce::from_slice(&nonce), decode(&file.ciphertext)?.as_ref())
+        .map_err(|_| invalid_data(
+            "Unable to decrypt the token file; is the passphrase correct?",
+        ))?;
+    serde_json::from_slice(&plaintext)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Get the passphrase from `CONF_SYNC_PASSPHRASE`, or ask for it on the
+/// terminal.
+pub fn read_passphrase() -> Result<String, io::Error> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    rpassword::prompt_password("Token file passphrase: ")
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key, io::Error> {
+    if passphrase.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The token file passphrase must not be empty",
+        ));
+    }
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    Ok(key)
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode(bytes)
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, io::Error> {
+    base64::decode(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("conf-sync-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn token(access_token: &str) -> TokenInfo {
+        TokenInfo {
+            access_token: access_token.to_owned(),
+            refresh_token: Some("refresh".to_owned()),
+            expires_at: None,
+            id_token: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn tokens_survive_reopening_with_the_same_passphrase() {
+        let filename = test_dir("encrypted-round-trip").join("keystorage.enc");
+        let storage = EncryptedFileStorage::new(filename.clone(), "passphrase").await.unwrap();
+        storage.set(&["a"], token("secret-access-token")).await.unwrap();
+
+        let contents = std::fs::read_to_string(&filename).unwrap();
+        assert!(!contents.contains("secret-access-token"));
+
+        let reopened = EncryptedFileStorage::new(filename, "passphrase").await.unwrap();
+        assert_eq!(reopened.get(&["a"]).await, Some(token("secret-access-token")));
+    }
+
+    #[tokio::test]
+    async fn a_wrong_passphrase_is_reported() {
+        let filename = test_dir("encrypted-wrong-passphrase").join("keystorage.enc");
+        let storage = EncryptedFileStorage::new(filename.clone(), "passphrase").await.unwrap();
+        storage.set(&["a"], token("access-token")).await.unwrap();
+
+        let error = EncryptedFileStorage::new(filename, "wrong").await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Unable to decrypt the token file; is the passphrase correct?");
+    }
+}
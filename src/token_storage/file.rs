@@ -0,0 +1,73 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use async_trait::async_trait;
+use yup_oauth2::storage::{
+    TokenStorage,
+    TokenInfo,
+};
+use super::json_tokens::{JSONToken, ScopeSet};
+use super::locked_file::{LockedFile, PlainJson};
+
+/// Stores tokens as plain JSON in a file only readable by the current user.
+/// Meant for containers and other hosts without a usable keyring.
+#[derive(Clone)]
+pub struct FileStorage {
+    file: LockedFile<PlainJson>,
+    filename: PathBuf,
+}
+
+impl FileStorage {
+    pub async fn new(filename: PathBuf) -> Result<Self, io::Error> {
+        Ok(FileStorage {
+            file: LockedFile::open(Some(filename.clone()), PlainJson).await?,
+            filename,
+        })
+    }
+
+    pub fn filename(&self) -> &Path {
+        &self.filename
+    }
+
+    pub async fn tokens(&self) -> Vec<JSONToken> {
+        self.file.current().await.iter().cloned().collect()
+    }
+
+    pub async fn find_token<T>(&self, scopes: &[T]) -> Option<JSONToken>
+    where
+        T: AsRef<str>,
+    {
+        self.file.current().await.find(ScopeSet::from(scopes)).cloned()
+    }
+
+    pub async fn remove_token<T>(&self, scopes: &[T]) -> anyhow::Result<Option<JSONToken>>
+    where
+        T: AsRef<str>,
+    {
+        let mut removed = None;
+        self.file
+            .update(|tokens| {
+                removed = tokens.remove(ScopeSet::from(scopes));
+                Ok(removed.is_some())
+            })
+            .await?;
+        Ok(removed)
+    }
+}
+
+#[async_trait]
+impl TokenStorage for FileStorage {
+    async fn set(
+        &self,
+        scopes: &[&str],
+        token: TokenInfo
+    ) -> anyhow::Result<()> {
+        self.file
+            .update(|tokens| tokens.set(ScopeSet::from(scopes), token).map(|()| true))
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, scopes: &[&str]) -> Option<TokenInfo> {
+        self.file.current().await.get(ScopeSet::from(scopes))
+    }
+}
@@ -6,6 +6,8 @@ use serde::{Deserialize, Serialize};
 use yup_oauth2::storage::{
     TokenInfo,
 };
+use crate::fs::write_file_atomic;
+use crate::scopes;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 enum FilterResponse {
@@ -46,15 +48,18 @@ pub struct ScopeSet<'a, T> {
 // Clone to be implemented regardless of whether T is Clone or not.
 impl<'a, T> Clone for ScopeSet<'a, T> {
     fn clone(&self) -> Self {
-        ScopeSet {
-            hash: self.hash,
-            filter: self.filter,
-            scopes: self.scopes,
-        }
+        *self
     }
 }
 impl<'a, T> Copy for ScopeSet<'a, T> {}
 
+impl<'a, T> ScopeSet<'a, T> {
+    /// The order independent fingerprint of these scopes.
+    pub fn hash(&self) -> ScopeHash {
+        self.hash
+    }
+}
+
 impl<'a, T> ScopeSet<'a, T>
 where
     T: AsRef<str>,
@@ -67,10 +72,12 @@ where
     // From trait. This inherent method just serves to auto deref from array
     // refs to slices and proxy to the From impl.
     pub fn from(scopes: &'a [T]) -> Self {
-        let (hash, filter) = scopes.iter().fold(
+        // Aliases and duplicates are folded first, since a duplicated scope
+        // would cancel itself out of the xor below.
+        let (hash, filter) = scopes::normalize(scopes).iter().fold(
             (ScopeHash(0), ScopeFilter(0)),
             |(mut scope_hash, mut scope_filter), scope| {
-                let h = seahash::hash(scope.as_ref().as_bytes());
+                let h = seahash::hash(scope.as_bytes());
 
                 // Use the first 4 6-bit chunks of the seahash as the 4 hash values
                 // in the bloom filter.
@@ -131,6 +138,9 @@ impl<'de> Deserialize<'de> for JSONToken {
             token: TokenInfo,
         }
         let RawJSONToken { scopes, token } = RawJSONToken::deserialize(deserializer)?;
+        // Tokens saved before scopes were normalized are migrated as they're
+        // read, so they're found under the same hash as new ones.
+        let scopes = scopes::normalize(&scopes);
         let ScopeSet { hash, filter, .. } = ScopeSet::from(&scopes);
         Ok(JSONToken {
             scopes,
@@ -141,21 +151,20 @@ impl<'de> Deserialize<'de> for JSONToken {
     }
 }
 
+impl JSONToken {
+    /// Whether the token can still be used, as it is or by refreshing it.
+    pub fn is_usable(&self) -> bool {
+        !self.token.is_expired() || self.token.refresh_token.is_some()
+    }
+}
+
 pub type JSONTokensMap = HashMap<ScopeHash, JSONToken>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct JSONTokens {
     token_map: JSONTokensMap,
 }
 
-impl Default for JSONTokens {
-    fn default() -> JSONTokens {
-        JSONTokens {
-            token_map: HashMap::new(),
-        }
-    }
-}
-
 impl Serialize for JSONTokens {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -185,9 +194,25 @@ impl<'de> Deserialize<'de> for JSONTokens {
             {
                 let mut token_map = HashMap::with_capacity(access.size_hint().unwrap_or(0));
                 while let Some(json_token) = access.next_element::<JSONToken>()? {
-                    token_map.insert(json_token.hash, json_token);
+                    // Expired tokens without a refresh token are dropped, so
+                    // they're pruned from the file the next time it's saved.
+                    if !json_token.is_usable() {
+                        continue;
+                    }
+                    // Tokens saved under two spellings of the same scopes
+                    // now share a hash; keep the one that lasts longest.
+                    let lifetime = |json_token: &JSONToken| {
+                        let token = &json_token.token;
+                        (token.refresh_token.is_some(), token.expires_at.is_none(), token.expires_at)
+                    };
+                    match token_map.get(&json_token.hash) {
+                        Some(existing) if lifetime(existing) >= lifetime(&json_token) => {},
+                        _ => {
+                            token_map.insert(json_token.hash, json_token);
+                        }
+                    }
                 }
-                Ok(JSONTokens { token_map, ..JSONTokens::default() })
+                Ok(JSONTokens { token_map })
             }
         }
 
@@ -207,37 +232,90 @@ impl JSONTokens {
         serde_json::from_slice(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
     }
 
+    /// Like `load_from_file`, but a token file that doesn't exist yet holds no
+    /// tokens.
+    pub async fn load_or_default(filename: &Path) -> Result<Self, io::Error> {
+        match JSONTokens::load_from_file(filename).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(JSONTokens::new()),
+            result => result,
+        }
+    }
+
+    /// Replace the token file, atomically so other conf-sync processes never
+    /// read it half written.
+    pub async fn save_to_file(&self, filename: &Path) -> Result<(), io::Error> {
+        let json = serde_json::to_string(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_file_atomic(filename, json.as_bytes()).await
+    }
+
     pub fn iter<'a>(&'a self) -> JSONTokenIterator<'a> {
         JSONTokenIterator::new(&self.token_map)
     }
 
-    pub fn get<T>(
+    pub fn get<T>(&self, scopes: ScopeSet<T>) -> Option<TokenInfo>
+    where
+        T: AsRef<str>,
+    {
+        self.find(scopes).map(|t: &JSONToken| t.token.clone())
+    }
+
+    /// Find the stored token that satisfies the requested scopes, along with
+    /// the scopes it was actually granted for.
+    pub fn find<T>(
         &self,
         ScopeSet {
             hash,
             filter,
             scopes,
         }: ScopeSet<T>,
-    ) -> Option<TokenInfo>
+    ) -> Option<&JSONToken>
     where
         T: AsRef<str>,
     {
-        if let Some(json_token) = self.token_map.get(&hash) {
-            return Some(json_token.token.clone());
-        }
-
         let requested_scopes_are_subset_of = |other_scopes: &[String]| {
             scopes
                 .iter()
-                .all(|s| other_scopes.iter().any(|t| t.as_str() == s.as_ref()))
+                .all(|s| other_scopes.iter().any(|t| t.as_str() == scopes::canonical(s.as_ref())))
+        };
+        // Prefer the token for exactly these scopes, then any token covering
+        // them that is still valid, then one that can be refreshed. Tokens that
+        // can't be used at all only come last, and ties go to the token that
+        // stays valid longest, so the choice doesn't depend on map order.
+        let rank = |json_token: &&JSONToken| {
+            let expires_at = json_token.token.expires_at;
+            (
+                json_token.is_usable(),
+                json_token.hash == hash,
+                !json_token.token.is_expired(),
+                expires_at.is_none(),
+                expires_at,
+                json_token.hash.0,
+            )
         };
-        // No exact match for the scopes provided. Search for any tokens that
-        // exist for a superset of the scopes requested.
         self.token_map
             .values()
-            .filter(|json_token| filter.is_subset_of(json_token.filter) == FilterResponse::Maybe)
-            .find(|v: &&JSONToken| requested_scopes_are_subset_of(&v.scopes))
-            .map(|t: &JSONToken| t.token.clone())
+            .filter(|json_token| {
+                json_token.hash == hash
+                    || (filter.is_subset_of(json_token.filter) == FilterResponse::Maybe
+                        && requested_scopes_are_subset_of(&json_token.scopes))
+            })
+            .max_by_key(rank)
+    }
+
+    /// Add a token that was loaded from elsewhere, replacing any token stored
+    /// for the same scopes.
+    pub fn insert(&mut self, json_token: JSONToken) {
+        self.token_map.insert(json_token.hash, json_token);
+    }
+
+    /// Remove the token that `find` would return for these scopes.
+    pub fn remove<T>(&mut self, scopes: ScopeSet<T>) -> Option<JSONToken>
+    where
+        T: AsRef<str>,
+    {
+        let hash = self.find(scopes)?.hash;
+        self.token_map.remove(&hash)
     }
 
     pub fn set<T>(
@@ -259,7 +337,7 @@ impl JSONTokens {
             }
             Entry::Vacant(entry) => {
                 let json_token = JSONToken {
-                    scopes: scopes.iter().map(|x| x.as_ref().to_owned()).collect(),
+                    scopes: scopes::normalize(scopes),
                     token,
                     hash,
                     filter,
@@ -272,17 +350,13 @@ impl JSONTokens {
 }
 
 pub struct JSONTokenIterator<'a> {
-    data: &'a JSONTokensMap,
     values: Values<'a, ScopeHash, JSONToken>,
-    iter: u32,
 }
 
 impl<'a> JSONTokenIterator<'a> {
     fn new(json_tokens: &'a JSONTokensMap) -> JSONTokenIterator<'a> {
         JSONTokenIterator {
-            data: json_tokens,
             values: json_tokens.values(),
-            iter: 0,
         }
     }
 }
@@ -298,4 +372,87 @@ impl<'a> Iterator for JSONTokenIterator<'a> {
             None => None
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use time::{Duration, OffsetDateTime};
+    use super::*;
+
+    fn token(access_token: &str, expires_in: Option<Duration>, refresh_token: Option<&str>) -> TokenInfo {
+        TokenInfo {
+            access_token: access_token.to_owned(),
+            refresh_token: refresh_token.map(str::to_owned),
+            expires_at: expires_in.map(|expires_in| OffsetDateTime::now_utc() + expires_in),
+            id_token: None,
+        }
+    }
+
+    fn valid(access_token: &str) -> TokenInfo {
+        token(access_token, Some(Duration::hours(1)), None)
+    }
+
+    fn access_token(tokens: &JSONTokens, scopes: &[&str]) -> Option<String> {
+        tokens.find(ScopeSet::from(scopes)).map(|json_token| json_token.token.access_token.clone())
+    }
+
+    #[test]
+    fn find_prefers_the_token_for_exactly_the_requested_scopes() {
+        let mut tokens = JSONTokens::new();
+        tokens.set(ScopeSet::from(&["a", "b"]), valid("superset")).unwrap();
+        tokens.set(ScopeSet::from(&["a"]), valid("exact")).unwrap();
+
+        assert_eq!(access_token(&tokens, &["a"]).as_deref(), Some("exact"));
+        assert_eq!(access_token(&tokens, &["b"]).as_deref(), Some("superset"));
+        assert_eq!(access_token(&tokens, &["c"]), None);
+    }
+
+    #[test]
+    fn find_prefers_a_valid_token_to_an_expired_one() {
+        let mut tokens = JSONTokens::new();
+        tokens
+            .set(ScopeSet::from(&["a", "b"]), token("expired", Some(-Duration::hours(1)), Some("refresh")))
+            .unwrap();
+        tokens.set(ScopeSet::from(&["a", "c"]), valid("valid")).unwrap();
+        tokens.set(ScopeSet::from(&["d"]), token("unusable", Some(-Duration::hours(1)), None)).unwrap();
+        tokens
+            .set(ScopeSet::from(&["d", "e"]), token("refreshable", Some(-Duration::hours(1)), Some("refresh")))
+            .unwrap();
+
+        assert_eq!(access_token(&tokens, &["a"]).as_deref(), Some("valid"));
+        assert_eq!(access_token(&tokens, &["d"]).as_deref(), Some("refreshable"));
+    }
+
+    #[test]
+    fn scope_aliases_order_and_duplicates_share_a_hash() {
+        const USERINFO_EMAIL: &str = "https://www.googleapis.com/auth/userinfo.email";
+        let hash = |scopes: &[&str]| ScopeSet::from(scopes).hash();
+        let expected = hash(&[USERINFO_EMAIL, scopes::OPENID]);
+
+        assert_eq!(hash(&["email", "openid"]), expected);
+        assert_eq!(hash(&["openid", "email"]), expected);
+        assert_eq!(hash(&["email", "openid", USERINFO_EMAIL, "openid"]), expected);
+        assert_ne!(hash(&["email"]), expected);
+        assert_eq!(
+            scopes::normalize(&["openid", "email", " email "]),
+            vec![USERINFO_EMAIL.to_owned(), scopes::OPENID.to_owned()],
+        );
+    }
+
+    #[test]
+    fn loading_prunes_tokens_that_can_no_longer_be_used() {
+        let mut tokens = JSONTokens::new();
+        tokens.set(ScopeSet::from(&["a"]), token("expired", Some(-Duration::hours(1)), None)).unwrap();
+        tokens
+            .set(ScopeSet::from(&["b"]), token("refreshable", Some(-Duration::hours(1)), Some("refresh")))
+            .unwrap();
+        tokens.set(ScopeSet::from(&["c"]), token("lasting", None, None)).unwrap();
+
+        let json = serde_json::to_string(&tokens).unwrap();
+        let loaded: JSONTokens = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(access_token(&loaded, &["a"]), None);
+        assert_eq!(access_token(&loaded, &["b"]).as_deref(), Some("refreshable"));
+        assert_eq!(access_token(&loaded, &["c"]).as_deref(), Some("lasting"));
+        assert_eq!(loaded.iter().count(), 2);
+    }
+}
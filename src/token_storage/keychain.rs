@@ -1,9 +1,33 @@
 extern crate keyring;
 use std::io;
-use keyring::{ Entry, credential::PlatformCredential };
+use keyring::{ Entry };
+use serde::{Deserialize, Serialize};
 use super::json_tokens::{ JSONToken, ScopeHash };
+use crate::auth::id_token_email;
 
 const SERVICE_NAME: &str = "remote_conf_sync";
+const CLIENT_SECRET_PREFIX: &str = "client_secret";
+const PROBE_KEY: &str = "probe";
+const INDEX_KEY: &str = "index";
+
+/// The keyring can't list its entries, so the tokens stored in it are recorded
+/// in an index entry of their own.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexEntry {
+    pub hash: u64,
+    pub scopes: Vec<String>,
+    pub account: Option<String>,
+}
+
+impl IndexEntry {
+    fn new(token: &JSONToken) -> Self {
+        IndexEntry {
+            hash: token.hash.0,
+            scopes: token.scopes.clone(),
+            account: token.token.id_token.as_deref().and_then(id_token_email),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Keychain {
@@ -21,17 +45,34 @@ impl Keychain {
         }
     }
 
+    /// The keychain for tokens of `profile`. Profiles can sign in to other
+    /// accounts, so each keeps its tokens apart from the others'.
+    pub fn for_profile(profile: Option<&str>) -> Keychain {
+        let mut keychain = Keychain::new();
+        if let Some(profile) = profile {
+            keychain.service = format!("{}:{}", SERVICE_NAME, profile);
+        }
+        keychain
+    }
+
+    /// Check that the keyring can be reached at all, e.g. that there is a
+    /// Secret Service daemon on D-Bus and its collection is unlocked.
+    pub fn probe(&self) -> Result<(), keyring::Error> {
+        let entry = self.entry(PROBE_KEY);
+        match entry.get_password() {
+            Ok(_) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Save `token` under `scope_hash`, overwriting whatever was stored there
+    /// before so refreshed access tokens replace stale ones.
     pub fn update_entry(
         &self,
         scope_hash: ScopeHash,
         token: &JSONToken
     ) -> Result<Entry, keyring::Error> {
-        let cred_key = scope_hash.0.to_string();
-        let entry = Entry::new_with_target(
-            &cred_key,
-            &self.service,
-            &self.user
-        );
+        let entry = self.entry(&scope_hash.0.to_string());
 
         let json = {
             serde_json::to_string(token)
@@ -40,18 +81,167 @@ impl Keychain {
                 ).unwrap()
         };
 
-        let existing = match entry.get_password_and_credential() {
-            Ok((existing_key, creds)) => {},
-            Err(e) => match e {
-                keyring::Error::NoEntry => {
-                    entry.set_password(&json)?;
-                },
-                _ => {
-                    panic!("{}: {}", "Error occurred while retrieving keychain entires", e);
-                }
-            }
+        match entry.get_password() {
+            Ok(existing) if existing == json => {},
+            Ok(_) | Err(keyring::Error::NoEntry) => {
+                entry.set_password(&json)?;
+            },
+            Err(e) => return Err(e),
         };
 
+        let mut index = self.read_index()?;
+        index.retain(|indexed| indexed.hash != scope_hash.0);
+        index.push(IndexEntry::new(token));
+        self.write_index(&index)?;
+
         Ok(entry)
     }
-}
\ No newline at end of file
+
+    /// Read the token stored under `scope_hash`, if there is one.
+    pub fn get_entry(
+        &self,
+        scope_hash: ScopeHash,
+    ) -> Result<Option<JSONToken>, keyring::Error> {
+        let entry = self.entry(&scope_hash.0.to_string());
+
+        let json = match entry.get_password() {
+            Ok(json) => json,
+            Err(keyring::Error::NoEntry) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        // An entry that no longer parses is treated as missing, so the user
+        // is asked to sign in again rather than being stuck with it.
+        Ok(serde_json::from_str(&json).ok())
+    }
+
+    /// Delete the token stored under `scope_hash`. Deleting an entry that
+    /// doesn't exist is not an error.
+    pub fn delete_entry(&self, scope_hash: ScopeHash) -> Result<(), keyring::Error> {
+        self.delete_password(scope_hash)?;
+
+        let mut index = self.read_index()?;
+        let indexed = index.len();
+        index.retain(|indexed| indexed.hash != scope_hash.0);
+        if index.len() != indexed {
+            self.write_index(&index)?;
+        }
+        Ok(())
+    }
+
+    /// Every usable token recorded in the index, leaving the keyring as it
+    /// is.
+    pub fn read_entries(&self) -> Result<Vec<JSONToken>, keyring::Error> {
+        let mut tokens = Vec::new();
+        for indexed in self.read_index()? {
+            match self.get_entry(ScopeHash(indexed.hash))? {
+                Some(token) if token.is_usable() => tokens.push(token),
+                _ => {},
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// Read every token recorded in the index, tidying the keyring as it
+    /// goes: index entries whose token has gone missing are dropped, tokens
+    /// that expired without a refresh token are deleted, and tokens filed
+    /// under an old hash are moved.
+    pub fn tidy_entries(&self) -> Result<Vec<JSONToken>, keyring::Error> {
+        let index = self.read_index()?;
+        let mut tokens = Vec::with_capacity(index.len());
+        let mut live = Vec::with_capacity(index.len());
+        let mut moved = Vec::new();
+
+        for indexed in index.iter() {
+            let stored_under = ScopeHash(indexed.hash);
+            match self.get_entry(stored_under)? {
+                Some(token) if !token.is_usable() => self.delete_password(stored_under)?,
+                // Tokens saved before scopes were normalized are filed under a
+                // hash that is no longer looked up, so they're moved.
+                Some(token) if token.hash != stored_under => {
+                    moved.push((stored_under, token.clone()));
+                    tokens.push(token);
+                }
+                Some(token) => {
+                    tokens.push(token);
+                    live.push(indexed.clone());
+                }
+                None => {},
+            }
+        }
+
+        if live.len() != index.len() {
+            self.write_index(&live)?;
+        }
+        for (stored_under, token) in moved {
+            self.update_entry(token.hash, &token)?;
+            self.delete_password(stored_under)?;
+        }
+        Ok(tokens)
+    }
+
+    fn delete_password(&self, scope_hash: ScopeHash) -> Result<(), keyring::Error> {
+        let entry = self.entry(&scope_hash.0.to_string());
+
+        match entry.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The index of stored tokens, without reading the tokens themselves.
+    pub fn read_index(&self) -> Result<Vec<IndexEntry>, keyring::Error> {
+        let entry = self.entry(INDEX_KEY);
+        match entry.get_password() {
+            // A corrupt index is rebuilt as tokens are written again.
+            Ok(json) => Ok(serde_json::from_str(&json).unwrap_or_default()),
+            Err(keyring::Error::NoEntry) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_index(&self, index: &[IndexEntry]) -> Result<(), keyring::Error> {
+        let entry = self.entry(INDEX_KEY);
+        if index.is_empty() {
+            return match entry.delete_password() {
+                Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+                Err(e) => Err(e),
+            };
+        }
+        let json = serde_json::to_string(index)
+            .expect("index entries always serialize");
+        entry.set_password(&json)
+    }
+
+    /// Store the OAuth client secret for `client_id`, replacing any secret
+    /// saved for it previously.
+    pub fn set_client_secret(
+        &self,
+        client_id: &str,
+        client_secret: &str,
+    ) -> Result<(), keyring::Error> {
+        self.client_secret_entry(client_id).set_password(client_secret)
+    }
+
+    /// Read the OAuth client secret for `client_id`, if one has been imported.
+    pub fn get_client_secret(
+        &self,
+        client_id: &str,
+    ) -> Result<Option<String>, keyring::Error> {
+        match self.client_secret_entry(client_id).get_password() {
+            Ok(secret) => Ok(Some(secret)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The keyring entry stored under `key`. The key is part of the service
+    /// name, as each entry needs a service of its own on every platform.
+    fn entry(&self, key: &str) -> Entry {
+        Entry::new(&format!("{}:{}", self.service, key), &self.user)
+    }
+
+    fn client_secret_entry(&self, client_id: &str) -> Entry {
+        self.entry(&format!("{}:{}", CLIENT_SECRET_PREFIX, client_id))
+    }
+}
@@ -0,0 +1,110 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use futures_locks::{Mutex, MutexGuard};
+use async_trait::async_trait;
+use super::json_tokens::JSONTokens;
+use crate::fs::FileLock;
+
+/// How tokens are laid out in a token file.
+#[async_trait]
+pub trait TokenFormat: Send + Sync {
+    /// Read the tokens in `filename`. A file that doesn't exist yet holds no
+    /// tokens.
+    async fn read(&self, filename: &Path) -> Result<JSONTokens, io::Error>;
+
+    /// Replace the contents of `filename` with `tokens`.
+    async fn write(&self, filename: &Path, tokens: &JSONTokens) -> Result<(), io::Error>;
+}
+
+/// Tokens as plain JSON.
+#[derive(Clone, Copy)]
+pub struct PlainJson;
+
+#[async_trait]
+impl TokenFormat for PlainJson {
+    async fn read(&self, filename: &Path) -> Result<JSONTokens, io::Error> {
+        JSONTokens::load_or_default(filename).await
+    }
+
+    async fn write(&self, filename: &Path, tokens: &JSONTokens) -> Result<(), io::Error> {
+        tokens.save_to_file(filename).await
+    }
+}
+
+/// The tokens in a token file that other conf-sync processes may be using at
+/// the same time, so it is locked whenever it is read or written. Without a
+/// file the tokens are only kept in memory.
+#[derive(Clone)]
+pub struct LockedFile<F> {
+    tokens: Mutex<JSONTokens>,
+    filename: Option<PathBuf>,
+    format: F,
+}
+
+impl<F: TokenFormat> LockedFile<F> {
+    pub async fn open(filename: Option<PathBuf>, format: F) -> Result<Self, io::Error> {
+        let tokens = match &filename {
+            Some(filename) => {
+                let _lock = FileLock::shared(filename).await?;
+                format.read(filename).await?
+            }
+            None => JSONTokens::new(),
+        };
+
+        Ok(LockedFile {
+            tokens: Mutex::new(tokens),
+            filename,
+            format,
+        })
+    }
+
+    pub fn filename(&self) -> Option<&Path> {
+        self.filename.as_deref()
+    }
+
+    pub fn format(&self) -> &F {
+        &self.format
+    }
+
+    /// The stored tokens, read again so tokens saved by other conf-sync
+    /// processes are seen. Keeps the tokens already loaded if the file can't
+    /// be read.
+    pub async fn current(&self) -> MutexGuard<JSONTokens> {
+        let mut tokens = self.tokens.lock().await;
+        let filename = match &self.filename {
+            Some(filename) => filename,
+            None => return tokens,
+        };
+
+        let reloaded = match FileLock::shared(filename).await {
+            Ok(_lock) => self.format.read(filename).await,
+            Err(e) => Err(e),
+        };
+        match reloaded {
+            Ok(reloaded) => *tokens = reloaded,
+            Err(e) => eprintln!("Unable to read {}: {}", filename.display(), e),
+        }
+        tokens
+    }
+
+    /// Apply `change` to the stored tokens, and write them back if it returns
+    /// true. The file stays locked from reading it until it is written, so a
+    /// token another process saved in the meantime isn't lost.
+    pub async fn update(
+        &self,
+        change: impl FnOnce(&mut JSONTokens) -> Result<bool, io::Error>,
+    ) -> Result<(), io::Error> {
+        let mut tokens = self.tokens.lock().await;
+        let filename = match &self.filename {
+            Some(filename) => filename,
+            None => return change(&mut tokens).map(|_| ()),
+        };
+
+        let _lock = FileLock::exclusive(filename).await?;
+        *tokens = self.format.read(filename).await?;
+        if change(&mut tokens)? {
+            self.format.write(filename, &tokens).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,56 @@
+use futures_locks::Mutex;
+use async_trait::async_trait;
+use yup_oauth2::storage::{
+    TokenStorage,
+    TokenInfo,
+};
+use super::json_tokens::{JSONTokens, JSONToken, ScopeSet};
+
+/// Keeps tokens for the lifetime of the process only. Every run has to sign
+/// in again.
+#[derive(Clone)]
+pub struct MemoryStorage {
+    tokens: Mutex<JSONTokens>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage {
+            tokens: Mutex::new(JSONTokens::new()),
+        }
+    }
+
+    pub async fn tokens(&self) -> Vec<JSONToken> {
+        self.tokens.lock().await.iter().cloned().collect()
+    }
+
+    pub async fn find_token<T>(&self, scopes: &[T]) -> Option<JSONToken>
+    where
+        T: AsRef<str>,
+    {
+        self.tokens.lock().await.find(ScopeSet::from(scopes)).cloned()
+    }
+
+    pub async fn remove_token<T>(&self, scopes: &[T]) -> anyhow::Result<Option<JSONToken>>
+    where
+        T: AsRef<str>,
+    {
+        Ok(self.tokens.lock().await.remove(ScopeSet::from(scopes)))
+    }
+}
+
+#[async_trait]
+impl TokenStorage for MemoryStorage {
+    async fn set(
+        &self,
+        scopes: &[&str],
+        token: TokenInfo
+    ) -> anyhow::Result<()> {
+        self.tokens.lock().await.set(ScopeSet::from(scopes), token)?;
+        Ok(())
+    }
+
+    async fn get(&self, scopes: &[&str]) -> Option<TokenInfo> {
+        self.tokens.lock().await.get(ScopeSet::from(scopes))
+    }
+}
@@ -1,109 +1,437 @@
 extern crate yup_oauth2;
 use std::io;
-use std::path::{PathBuf};
-use futures_locks::Mutex;
+use std::path::{Path, PathBuf};
+use anyhow::bail;
 use async_trait::async_trait;
+use serde_derive::{ Deserialize, Serialize };
 use yup_oauth2::storage::{
     TokenStorage,
     TokenInfo,
 };
 
+mod encrypted_file;
+mod file;
 mod json_tokens;
 mod keychain;
+mod locked_file;
+mod memory;
 
-use json_tokens::{JSONTokens, ScopeSet, JSONToken};
-use keychain::{Keychain};
-use crate::fs::{open_writeable_file};
+use json_tokens::{JSONTokens, ScopeSet};
+use locked_file::{LockedFile, PlainJson};
+pub use encrypted_file::EncryptedFileStorage;
+pub use file::FileStorage;
+pub use json_tokens::JSONToken;
+pub use keychain::Keychain;
+pub use memory::MemoryStorage;
 
-type TokenCollection = Mutex<JSONTokens>;
+/// Where OAuth tokens are kept between runs.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenBackend {
+    /// The operating system's keyring.
+    #[default]
+    Keyring,
+    /// A file encrypted with a passphrase, for hosts without a keyring.
+    #[serde(alias = "encrypted-file")]
+    EncryptedFile,
+    /// A plain JSON file, for containers without a keyring.
+    File,
+    /// Nothing is persisted; every run signs in again.
+    Memory,
+}
+
+impl std::str::FromStr for TokenBackend {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.replace('-', "_").as_str() {
+            "keyring" => Ok(TokenBackend::Keyring),
+            "encrypted_file" => Ok(TokenBackend::EncryptedFile),
+            "file" => Ok(TokenBackend::File),
+            "memory" => Ok(TokenBackend::Memory),
+            _ => Err(format!(
+                "unknown token storage '{}', expected one of keyring, encrypted-file, file, memory",
+                value,
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for TokenBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            TokenBackend::Keyring => "keyring",
+            TokenBackend::EncryptedFile => "encrypted-file",
+            TokenBackend::File => "file",
+            TokenBackend::Memory => "memory",
+        })
+    }
+}
+
+impl TokenBackend {
+    /// Name of the token file used when the settings don't give a path.
+    pub fn default_filename(self) -> &'static str {
+        match self {
+            TokenBackend::EncryptedFile => "keystorage.enc",
+            _ => "keystorage.json",
+        }
+    }
+}
+
+/// The token storage selected in the settings. The authenticator and
+/// conf-sync share one store by cloning it.
+#[derive(Clone)]
+pub enum TokenStore {
+    Keyring(KeychainStorage),
+    EncryptedFile(EncryptedFileStorage),
+    File(FileStorage),
+    Memory(MemoryStorage),
+}
+
+impl TokenStore {
+    /// Open the token storage for `backend`. `filename` is used by the
+    /// backends that keep tokens in a file, and by the keyring alongside the
+    /// keychain; `keychain` is used by the keyring.
+    pub async fn open(
+        backend: TokenBackend,
+        filename: PathBuf,
+        keychain: Keychain,
+    ) -> Result<Self, io::Error> {
+        Ok(match backend {
+            TokenBackend::Keyring => {
+                TokenStore::Keyring(KeychainStorage::new(keychain, Some(filename)).await?)
+            }
+            TokenBackend::EncryptedFile => {
+                let passphrase = encrypted_file::read_passphrase()?;
+                TokenStore::EncryptedFile(EncryptedFileStorage::new(filename, &passphrase).await?)
+            }
+            TokenBackend::File => TokenStore::File(FileStorage::new(filename).await?),
+            TokenBackend::Memory => TokenStore::Memory(MemoryStorage::new()),
+        })
+    }
+
+    /// Open the storage for `backend` like `open`, but only to look at the
+    /// tokens: the keyring and its token file are left as they are rather
+    /// than being brought in sync and tidied.
+    pub async fn open_read_only(
+        backend: TokenBackend,
+        filename: PathBuf,
+        keychain: Keychain,
+    ) -> Result<Self, io::Error> {
+        match backend {
+            TokenBackend::Keyring => {
+                Ok(TokenStore::Keyring(KeychainStorage::read_only(keychain, Some(filename)).await?))
+            }
+            _ => TokenStore::open(backend, filename, keychain).await,
+        }
+    }
+
+    /// Open the same storage again, so reads reflect what was persisted
+    /// rather than what this handle has cached.
+    pub async fn reopen(&self) -> Result<Self, io::Error> {
+        Ok(match self {
+            TokenStore::Keyring(storage) => {
+                let filename = storage.filename().map(Path::to_path_buf);
+                TokenStore::Keyring(KeychainStorage::new(storage.keychain.clone(), filename).await?)
+            }
+            TokenStore::EncryptedFile(storage) => TokenStore::EncryptedFile(storage.reload().await?),
+            TokenStore::File(storage) => {
+                TokenStore::File(FileStorage::new(storage.filename().to_owned()).await?)
+            }
+            TokenStore::Memory(storage) => TokenStore::Memory(storage.clone()),
+        })
+    }
+
+    /// Describe where tokens are being kept, for display to the user.
+    pub fn describe(&self) -> String {
+        match self {
+            TokenStore::Keyring(storage) => storage.describe(),
+            TokenStore::EncryptedFile(storage) => {
+                format!("{} (encrypted)", storage.filename().display())
+            }
+            TokenStore::File(storage) => storage.filename().display().to_string(),
+            TokenStore::Memory(_) => "memory only".into(),
+        }
+    }
+
+    /// The file tokens are kept in, if any. The keyring's is kept alongside
+    /// the keychain.
+    pub fn filename(&self) -> Option<&Path> {
+        match self {
+            TokenStore::Keyring(storage) => storage.filename(),
+            TokenStore::EncryptedFile(storage) => Some(storage.filename()),
+            TokenStore::File(storage) => Some(storage.filename()),
+            TokenStore::Memory(_) => None,
+        }
+    }
+
+    /// Look up the stored token covering `scopes`, including the scopes it
+    /// was granted for. Used to report on the token without refreshing it.
+    pub async fn find_token<T>(&self, scopes: &[T]) -> Option<JSONToken>
+    where
+        T: AsRef<str>,
+    {
+        match self {
+            TokenStore::Keyring(storage) => storage.find_token(scopes).await,
+            TokenStore::EncryptedFile(storage) => storage.find_token(scopes).await,
+            TokenStore::File(storage) => storage.find_token(scopes).await,
+            TokenStore::Memory(storage) => storage.find_token(scopes).await,
+        }
+    }
+
+    /// Every stored token.
+    pub async fn tokens(&self) -> anyhow::Result<Vec<JSONToken>> {
+        match self {
+            TokenStore::Keyring(storage) => storage.tokens().await,
+            TokenStore::EncryptedFile(storage) => Ok(storage.tokens().await),
+            TokenStore::File(storage) => Ok(storage.tokens().await),
+            TokenStore::Memory(storage) => Ok(storage.tokens().await),
+        }
+    }
+
+    /// Remove every stored token, returning how many there were.
+    pub async fn clear(&self) -> anyhow::Result<usize> {
+        let tokens = self.tokens().await?;
+        for json_token in tokens.iter() {
+            self.remove_token(&json_token.scopes).await?;
+        }
+        Ok(tokens.len())
+    }
+
+    /// Forget the token covering `scopes`. Used when the token has been
+    /// revoked.
+    pub async fn remove_token<T>(&self, scopes: &[T]) -> anyhow::Result<Option<JSONToken>>
+    where
+        T: AsRef<str>,
+    {
+        match self {
+            TokenStore::Keyring(storage) => storage.remove_token(scopes).await,
+            TokenStore::EncryptedFile(storage) => storage.remove_token(scopes).await,
+            TokenStore::File(storage) => storage.remove_token(scopes).await,
+            TokenStore::Memory(storage) => storage.remove_token(scopes).await,
+        }
+    }
+}
 
+#[async_trait]
+impl TokenStorage for TokenStore {
+    async fn set(
+        &self,
+        scopes: &[&str],
+        token: TokenInfo
+    ) -> anyhow::Result<()> {
+        match self {
+            TokenStore::Keyring(storage) => storage.set(scopes, token).await,
+            TokenStore::EncryptedFile(storage) => storage.set(scopes, token).await,
+            TokenStore::File(storage) => storage.set(scopes, token).await,
+            TokenStore::Memory(storage) => storage.set(scopes, token).await,
+        }
+    }
+
+    async fn get(&self, scopes: &[&str]) -> Option<TokenInfo> {
+        match self {
+            TokenStore::Keyring(storage) => storage.get(scopes).await,
+            TokenStore::EncryptedFile(storage) => storage.get(scopes).await,
+            TokenStore::File(storage) => storage.get(scopes).await,
+            TokenStore::Memory(storage) => storage.get(scopes).await,
+        }
+    }
+}
+
+/// Stores tokens in the OS keyring. When a `filename` is given, tokens are
+/// written to that file as well, so they're still there while the keyring
+/// can't be reached. Whenever both work they're brought back in sync.
 #[derive(Clone)]
 pub struct KeychainStorage {
-    tokens: TokenCollection,
+    file: LockedFile<PlainJson>,
     keychain: Keychain,
-    filename: Option<PathBuf>,
+    keychain_available: bool,
+    read_only: bool,
 }
 
 impl KeychainStorage {
-    pub async fn new(filename: Option<PathBuf>) -> Result<Self, io::Error> {
-        let keychain = Keychain::new();
+    pub async fn new(keychain: Keychain, filename: Option<PathBuf>) -> Result<Self, io::Error> {
+        KeychainStorage::open(keychain, filename, false).await
+    }
 
-        Ok(KeychainStorage {
-            keychain,
-            filename: None,
-            tokens: Mutex::new(JSONTokens::new()),
-        })
+    /// Open the storage only to look at the tokens, without changing the
+    /// keyring or the token file.
+    pub async fn read_only(keychain: Keychain, filename: Option<PathBuf>) -> Result<Self, io::Error> {
+        KeychainStorage::open(keychain, filename, true).await
     }
 
-    pub async fn hydrate_from_file(&mut self, filename: PathBuf) -> Result<Self, io::Error> {
-        let fs_tokens = match JSONTokens::load_from_file(&filename).await {
-            Ok(tokens) => tokens,
-            Err(e) if e.kind() == io::ErrorKind::NotFound => JSONTokens::new(),
-            Err(e) => return Err(e),
+    async fn open(
+        keychain: Keychain,
+        filename: Option<PathBuf>,
+        read_only: bool,
+    ) -> Result<Self, io::Error> {
+        let keychain_available = match keychain.probe() {
+            Ok(()) => {
+                match &filename {
+                    Some(fname) => eprintln!("Storing tokens in the keyring and {}", fname.display()),
+                    None => eprintln!("Storing tokens in the keyring"),
+                }
+                true
+            }
+            Err(e) => {
+                match &filename {
+                    Some(fname) => eprintln!(
+                        "The keyring is unavailable ({}); storing tokens in {}",
+                        e,
+                        fname.display(),
+                    ),
+                    None => eprintln!(
+                        "The keyring is unavailable ({}); tokens will not be saved",
+                        e,
+                    ),
+                }
+                false
+            }
         };
 
-        let mut tokens = self.tokens.lock().await;
+        let storage = KeychainStorage {
+            file: LockedFile::open(filename, PlainJson).await?,
+            keychain,
+            keychain_available,
+            read_only,
+        };
+        if storage.keychain_available && !read_only {
+            storage.sync_with_file().await?;
+        }
+        Ok(storage)
+    }
+
+    /// Whether the keyring could be reached when this storage was created.
+    pub fn is_available(&self) -> bool {
+        self.keychain_available
+    }
 
-        for json_token in fs_tokens.iter() {
-            let scope_set = ScopeSet::from(&json_token.scopes);
-            tokens.set(scope_set, json_token.token.clone()).unwrap();
+    /// The token file kept alongside the keyring, if any.
+    pub fn filename(&self) -> Option<&Path> {
+        self.file.filename()
+    }
+
+    /// Describe where tokens are being kept, for display to the user.
+    pub fn describe(&self) -> String {
+        match (self.keychain_available, self.file.filename()) {
+            (true, Some(fname)) => format!("keyring and {}", fname.display()),
+            (true, None) => "keyring".into(),
+            (false, Some(fname)) => format!("{} (keyring unavailable)", fname.display()),
+            (false, None) => "memory only (keyring unavailable)".into(),
         }
+    }
 
-        let _ = &self.update_keychain();
+    /// Copy the tokens saved in the keyring or the token file while the other
+    /// couldn't be written into both. Reading every keychain entry also moves
+    /// tokens saved before scopes were normalized to the hash they're looked
+    /// up under now.
+    async fn sync_with_file(&self) -> Result<(), io::Error> {
+        self.file
+            .update(|tokens| {
+                let mut file_changed = false;
+                match self.keychain.tidy_entries() {
+                    Ok(entries) => {
+                        for json_token in entries {
+                            if !tokens.iter().any(|loaded| loaded.hash == json_token.hash) {
+                                tokens.insert(json_token);
+                                file_changed = true;
+                            }
+                        }
+                    }
+                    Err(e) => eprintln!("Unable to read tokens from the keychain: {}", e),
+                }
 
-        Ok(self.to_owned())
+                for json_token in tokens.iter() {
+                    if let Err(e) = self.keychain.update_entry(json_token.hash, json_token) {
+                        eprintln!("Unable to save tokens to the keychain: {}", e);
+                        break;
+                    }
+                }
+                Ok(file_changed)
+            })
+            .await
     }
 
-    pub async fn update_keychain(&self) -> Result<Self, keyring::Error> {
-        let tokens = self.tokens.lock().await;
+    /// The token covering `scopes`, with tokens other conf-sync processes
+    /// saved to the token file picked up. Tokens saved by an earlier run
+    /// live in the keychain until they're first asked for, so the entry for
+    /// these scopes is read if no token is loaded for them.
+    async fn lookup<T>(&self, scopes: ScopeSet<'_, T>) -> Option<JSONToken>
+    where
+        T: AsRef<str>,
+    {
+        let mut tokens = self.file.current().await;
+        self.load_from_keychain(&mut tokens, scopes);
+        tokens.find(scopes).cloned()
+    }
 
-        for json_token in tokens.iter() {
-            let _ = &self.keychain.update_entry(
-                json_token.hash,
-                json_token
-            )?;
+    fn load_from_keychain<T>(&self, tokens: &mut JSONTokens, scopes: ScopeSet<'_, T>)
+    where
+        T: AsRef<str>,
+    {
+        if !self.keychain_available || tokens.find(scopes).is_some() {
+            return;
         }
 
-        Ok(self.to_owned())
+        match self.keychain.get_entry(scopes.hash()) {
+            Ok(Some(json_token)) if json_token.is_usable() => tokens.insert(json_token),
+            Ok(_) => {},
+            Err(e) => eprintln!("Unable to read token from the keychain: {}", e),
+        }
     }
 
-    async fn set_token<T>(
-        &self,
-        scopes: ScopeSet<'_, T>,
-        token: TokenInfo,
-    ) -> anyhow::Result<(), anyhow::Error>
+    /// Look up the stored token covering `scopes`, including the scopes it
+    /// was granted for. Used to report on the token without refreshing it.
+    pub async fn find_token<T>(&self, scopes: &[T]) -> Option<JSONToken>
     where
         T: AsRef<str>,
     {
-        let filename = match &self.filename {
-            Some(fname) => fname,
-            None => return Ok(())
-        };
-
-        use tokio::io::AsyncWriteExt;
-        let json = {
-            use std::ops::Deref;
-            let mut lock = self.tokens.lock().await;
-            lock.set(scopes, token)?;
-            serde_json::to_string(lock.deref())
-                .map_err(
-                    |e| io::Error::new(io::ErrorKind::InvalidData, e)
-                )?
-        };
+        self.lookup(ScopeSet::from(scopes)).await
+    }
 
-        let mut f = open_writeable_file(&filename).await?;
-        f.write_all(json.as_bytes()).await?;
-        Ok(())
+    /// Every stored token, including those only saved in the keychain by an
+    /// earlier run.
+    pub async fn tokens(&self) -> anyhow::Result<Vec<JSONToken>> {
+        let mut tokens = self.file.current().await;
+        if self.keychain_available {
+            let entries = if self.read_only {
+                self.keychain.read_entries()?
+            } else {
+                self.keychain.tidy_entries()?
+            };
+            for json_token in entries {
+                if !tokens.iter().any(|loaded| loaded.hash == json_token.hash) {
+                    tokens.insert(json_token);
+                }
+            }
+        }
+        Ok(tokens.iter().cloned().collect())
     }
 
-    async fn get_token<T>(
-        &self,
-        scopes: ScopeSet<'_, T>
-    ) -> Option<TokenInfo>
+    /// Forget the token covering `scopes`, removing it from the keychain and
+    /// the token file as well. Used when the token has been revoked.
+    pub async fn remove_token<T>(&self, scopes: &[T]) -> anyhow::Result<Option<JSONToken>>
     where
         T: AsRef<str>,
     {
-        self.tokens.lock().await.get(scopes)
+        if self.read_only {
+            bail!("The keyring was opened read only; tokens can't be removed");
+        }
+        let scope_set = ScopeSet::from(scopes);
+        let mut removed = None;
+        self.file
+            .update(|tokens| {
+                // Make sure a token that is only in the keychain gets removed too.
+                self.load_from_keychain(tokens, scope_set);
+                removed = tokens.remove(scope_set);
+                Ok(removed.is_some())
+            })
+            .await?;
+
+        if let (Some(json_token), true) = (&removed, self.keychain_available) {
+            self.keychain.delete_entry(json_token.hash)?;
+        }
+        Ok(removed)
     }
 }
 
@@ -114,12 +442,30 @@ impl TokenStorage for KeychainStorage {
         scopes: &[&str],
         token: TokenInfo
     ) -> anyhow::Result<()> {
+        if self.read_only {
+            bail!("The keyring was opened read only; tokens can't be saved");
+        }
         let scope_set = ScopeSet::from(scopes);
-        self.set_token(scope_set, token).await
+        let mut json_token = None;
+        self.file
+            .update(|tokens| {
+                tokens.set(scope_set, token)?;
+                json_token = tokens.iter().find(|json_token| json_token.hash == scope_set.hash()).cloned();
+                Ok(true)
+            })
+            .await?;
+
+        // The token is still usable for this run if the keychain can't be
+        // written, so report the failure instead of failing the request.
+        if let Some(json_token) = json_token.filter(|_| self.keychain_available) {
+            if let Err(e) = self.keychain.update_entry(json_token.hash, &json_token) {
+                eprintln!("Unable to save token to the keychain: {}", e);
+            }
+        }
+        Ok(())
     }
 
     async fn get(&self, scopes: &[&str]) -> Option<TokenInfo> {
-        let scope_set = ScopeSet::from(scopes);
-        self.get_token(scope_set).await
+        self.lookup(ScopeSet::from(scopes)).await.map(|json_token| json_token.token)
     }
 }
\ No newline at end of file